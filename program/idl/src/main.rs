@@ -0,0 +1,273 @@
+//! Prints the Z-Fubao IDL as JSON.
+//!
+//! Usage: `cargo run -p z-fubao-idl [-- <output path>]`
+//!
+//! Instruction arguments, account layouts and events come from the Borsh
+//! schemas of the program types, account lists from `z_fubao::cpi` and error
+//! codes from `ZFubaoError`, so regenerating after a program change keeps
+//! clients in sync.
+
+use std::collections::BTreeMap;
+
+use borsh::{
+    BorshSchema,
+    schema::{BorshSchemaContainer, Declaration, Definition, Fields},
+};
+use serde_json::{Value, json};
+use z_fubao::{
+    cpi::INSTRUCTION_ACCOUNTS,
+    error::ZFubaoError,
+    events::ZFubaoEvent,
+    instructions::ZFubaoInstruction,
+    state::{Auction, Market, Obligation, ObligationHealth, Staker, Trigger, ZFubaoConfig},
+};
+
+fn main() {
+    let idl = serde_json::to_string_pretty(&build_idl()).expect("IDL serializes to JSON");
+
+    match std::env::args().nth(1) {
+        Some(path) => std::fs::write(&path, idl + "\n").expect("failed to write the IDL"),
+        None => println!("{}", idl),
+    }
+}
+
+fn build_idl() -> Value {
+    let mut types = BTreeMap::new();
+
+    let instruction_schema = collect_schema::<ZFubaoInstruction>(&mut types);
+    let event_schema = collect_schema::<ZFubaoEvent>(&mut types);
+    collect_schema::<ZFubaoConfig>(&mut types);
+    collect_schema::<Market>(&mut types);
+    collect_schema::<Obligation>(&mut types);
+    collect_schema::<Staker>(&mut types);
+    collect_schema::<Auction>(&mut types);
+    collect_schema::<Trigger>(&mut types);
+    collect_schema::<ObligationHealth>(&mut types);
+
+    let instructions = enum_variants(&instruction_schema)
+        .into_iter()
+        .enumerate()
+        .map(|(index, (discriminant, name, args))| {
+            // cpi::INSTRUCTION_ACCOUNTS has to follow the enum, or the IDL would lie
+            let (accounts_name, accounts) = INSTRUCTION_ACCOUNTS
+                .get(index)
+                .unwrap_or_else(|| panic!("no accounts listed for instruction {}", name));
+            assert_eq!(
+                *accounts_name, name,
+                "cpi::INSTRUCTION_ACCOUNTS is out of order with ZFubaoInstruction"
+            );
+
+            let mut instruction = json!({
+                "name": name,
+                "discriminant": discriminant,
+                "args": args,
+                "accounts": accounts
+                    .iter()
+                    .map(|account| json!({
+                        "name": account.name,
+                        "signer": account.signer,
+                        "writable": account.writable,
+                    }))
+                    .collect::<Vec<_>>(),
+            });
+            if let Some(remaining_accounts) = remaining_accounts(&name) {
+                instruction["remainingAccounts"] = remaining_accounts;
+            }
+            if name == "GetObligationHealth" {
+                instruction["returns"] = json!(ObligationHealth::declaration());
+            }
+            instruction
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        instructions.len(),
+        INSTRUCTION_ACCOUNTS.len(),
+        "cpi::INSTRUCTION_ACCOUNTS lists instructions that do not exist"
+    );
+
+    let events = enum_variants(&event_schema)
+        .into_iter()
+        .map(|(discriminant, name, fields)| {
+            json!({
+                "name": name,
+                "discriminant": discriminant,
+                "fields": fields,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let errors = ZFubaoError::ALL
+        .iter()
+        .map(|error| {
+            json!({
+                "code": *error as u32,
+                "name": format!("{:?}", error),
+                "msg": error.to_string(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "name": "z_fubao",
+        "version": env!("CARGO_PKG_VERSION"),
+        "instructions": instructions,
+        "accounts": [
+            {
+                "name": ZFubaoConfig::declaration(),
+                "size": ZFubaoConfig::LEN,
+                "fields": struct_fields(&types, &ZFubaoConfig::declaration()),
+            },
+            {
+                "name": Market::declaration(),
+                "size": Market::LEN,
+                "fields": struct_fields(&types, &Market::declaration()),
+            },
+            {
+                "name": Obligation::declaration(),
+                "size": Obligation::LEN,
+                "fields": struct_fields(&types, &Obligation::declaration()),
+            },
+            {
+                "name": Staker::declaration(),
+                "size": Staker::LEN,
+                "fields": struct_fields(&types, &Staker::declaration()),
+            },
+            {
+                "name": Auction::declaration(),
+                "size": Auction::LEN,
+                "fields": struct_fields(&types, &Auction::declaration()),
+            },
+            {
+                "name": Trigger::declaration(),
+                "size": Trigger::LEN,
+                "fields": struct_fields(&types, &Trigger::declaration()),
+            },
+        ],
+        "events": events,
+        "errors": errors,
+        "types": types,
+    })
+}
+
+// Accounts some instructions take after their fixed ones, see z_fubao::cpi
+fn remaining_accounts(instruction: &str) -> Option<Value> {
+    match instruction {
+        // The markets of the obligation's other deposits, in deposit order. Liquidate,
+        // Deleverage and ExecuteTrigger take the SZUSD market after them.
+        "WithdrawZBTC"
+        | "BorrowZUSD"
+        | "DepositAndBorrow"
+        | "RepayAndWithdraw"
+        | "GetObligationHealth"
+        | "Liquidate"
+        | "StartAuction"
+        | "Deleverage"
+        | "ExecuteTrigger" => Some(json!({
+            "name": "deposit_markets",
+            "signer": false,
+            "writable": false,
+        })),
+        // The obligations to redeem from, riskiest first
+        "Redeem" => Some(json!({
+            "name": "obligations",
+            "signer": false,
+            "writable": true,
+        })),
+        // As above, but written to as collateral is seized
+        "SettleObligation" => Some(json!({
+            "name": "deposit_markets",
+            "signer": false,
+            "writable": true,
+        })),
+        // The markets to settle
+        "GlobalSettlement" => Some(json!({
+            "name": "markets",
+            "signer": false,
+            "writable": true,
+        })),
+        _ => None,
+    }
+}
+
+// Adds every definition T depends on to `types` and returns its container
+fn collect_schema<T: BorshSchema>(
+    types: &mut BTreeMap<Declaration, Value>,
+) -> BorshSchemaContainer {
+    let container = BorshSchemaContainer::for_type::<T>();
+    for (declaration, definition) in container.definitions() {
+        types.insert(declaration.clone(), definition_to_json(definition));
+    }
+    container
+}
+
+// (discriminant, name, fields) of each variant of an enum schema
+fn enum_variants(container: &BorshSchemaContainer) -> Vec<(i64, String, Value)> {
+    let Some(Definition::Enum { variants, .. }) = container.get_definition(container.declaration())
+    else {
+        panic!("{} is not an enum", container.declaration());
+    };
+
+    variants
+        .iter()
+        .map(|(discriminant, name, declaration)| {
+            let fields = match container.get_definition(declaration) {
+                Some(Definition::Struct { fields }) => fields_to_json(fields),
+                _ => json!([]),
+            };
+            (*discriminant, name.clone(), fields)
+        })
+        .collect()
+}
+
+fn struct_fields(types: &BTreeMap<Declaration, Value>, declaration: &Declaration) -> Value {
+    types[declaration]["fields"].clone()
+}
+
+fn fields_to_json(fields: &Fields) -> Value {
+    match fields {
+        Fields::NamedFields(fields) => fields
+            .iter()
+            .map(|(name, declaration)| json!({ "name": name, "type": declaration }))
+            .collect(),
+        Fields::UnnamedFields(fields) => fields
+            .iter()
+            .map(|declaration| json!({ "type": declaration }))
+            .collect(),
+        Fields::Empty => json!([]),
+    }
+}
+
+fn definition_to_json(definition: &Definition) -> Value {
+    match definition {
+        Definition::Primitive(size) => json!({ "kind": "primitive", "size": size }),
+        Definition::Sequence {
+            length_width,
+            length_range,
+            elements,
+        } => json!({
+            "kind": "sequence",
+            "lengthWidth": length_width,
+            "lengthRange": [length_range.start(), length_range.end()],
+            "elements": elements,
+        }),
+        Definition::Tuple { elements } => json!({ "kind": "tuple", "elements": elements }),
+        Definition::Enum {
+            tag_width,
+            variants,
+        } => json!({
+            "kind": "enum",
+            "tagWidth": tag_width,
+            "variants": variants
+                .iter()
+                .map(|(discriminant, name, declaration)| json!({
+                    "discriminant": discriminant,
+                    "name": name,
+                    "type": declaration,
+                }))
+                .collect::<Vec<_>>(),
+        }),
+        Definition::Struct { fields } => {
+            json!({ "kind": "struct", "fields": fields_to_json(fields) })
+        }
+    }
+}
@@ -0,0 +1,1312 @@
+//! Helpers for calling Z-Fubao from other programs.
+//!
+//! Each instruction has a typed account struct listing the accounts in the
+//! order the processor expects, and a function that builds the instruction
+//! and invokes it. Pass the seeds of any PDA that has to sign (e.g. a vault
+//! program owning the obligation) as `signer_seeds`, or `&[]` otherwise.
+//! Instructions that check the LTV also take `deposit_markets`, the markets of
+//! the obligation's deposits outside the market passed in `accounts`, in
+//! deposit order. It is empty while all collateral sits in one market.
+//!
+//! Build with the `cpi` feature so the Z-Fubao entrypoint is left out of the
+//! calling program.
+
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
+    program::{get_return_data, invoke_signed},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use borsh::BorshDeserialize;
+
+use crate::{
+    instructions::ZFubaoInstruction,
+    state::{DelegatePermission, ObligationHealth},
+};
+
+/// How an instruction uses one of its accounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountSpec {
+    pub name: &'static str,
+    pub signer: bool,
+    pub writable: bool,
+}
+
+// Declares an account struct for one instruction. Every field is tagged with
+// how the program uses it: `signer`, `signer_writable`, `writable` or `readonly`.
+macro_rules! cpi_accounts {
+    (
+        $(#[$struct_doc:meta])*
+        $name:ident {
+            $($field:ident: $kind:ident,)*
+        }
+    ) => {
+        $(#[$struct_doc])*
+        pub struct $name<'a, 'info> {
+            $(pub $field: &'a AccountInfo<'info>,)*
+        }
+
+        impl<'info> $name<'_, 'info> {
+            /// The accounts in the order the program expects them
+            pub const ACCOUNTS: &'static [AccountSpec] = &[
+                $(cpi_accounts!(@spec $kind, $field),)*
+            ];
+
+            pub fn to_account_metas(&self) -> Vec<AccountMeta> {
+                vec![$(cpi_accounts!(@meta $kind, self.$field.key),)*]
+            }
+
+            pub fn to_account_infos(&self) -> Vec<AccountInfo<'info>> {
+                vec![$(self.$field.clone(),)*]
+            }
+        }
+    };
+    (@spec $kind:ident, $field:ident) => {
+        AccountSpec {
+            name: stringify!($field),
+            signer: cpi_accounts!(@signer $kind),
+            writable: cpi_accounts!(@writable $kind),
+        }
+    };
+    (@signer signer) => { true };
+    (@signer signer_writable) => { true };
+    (@signer $kind:ident) => { false };
+    (@writable writable) => { true };
+    (@writable signer_writable) => { true };
+    (@writable $kind:ident) => { false };
+    (@meta signer, $key:expr) => {
+        AccountMeta::new_readonly(*$key, true)
+    };
+    (@meta signer_writable, $key:expr) => {
+        AccountMeta::new(*$key, true)
+    };
+    (@meta writable, $key:expr) => {
+        AccountMeta::new(*$key, false)
+    };
+    (@meta readonly, $key:expr) => {
+        AccountMeta::new_readonly(*$key, false)
+    };
+}
+
+cpi_accounts! {
+    /// Accounts for `Initialize`
+    Initialize {
+        admin: signer_writable,
+        authority: writable,
+        global_config: writable,
+        zusd_mint: readonly,
+        szusd_mint: readonly,
+        system_program: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `InitObligation`
+    InitObligation {
+        user: signer_writable,
+        authority: readonly,
+        market: readonly,
+        obligation: writable,
+        system_program: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `DepositZBTC`, the payer can fund any obligation
+    DepositZbtc {
+        payer: signer,
+        authority: readonly,
+        market: writable,
+        obligation: writable,
+        payer_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `WithdrawZBTC`
+    WithdrawZbtc {
+        user: signer,
+        authority: readonly,
+        market: writable,
+        obligation: writable,
+        user_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `BorrowZUSD`
+    BorrowZusd {
+        user: signer,
+        authority: readonly,
+        market: writable,
+        obligation: writable,
+        user_zusd: writable,
+        zusd_mint: writable,
+        token_program: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `RepayZUSD`, the payer can repay any obligation
+    RepayZusd {
+        payer: signer,
+        authority: readonly,
+        market: writable,
+        obligation: writable,
+        payer_zusd: writable,
+        zusd_mint: writable,
+        token_program: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `Stake` and `Unstake`
+    Stake {
+        user: signer,
+        authority: readonly,
+        global_config: writable,
+        user_zusd: writable,
+        user_szusd: writable,
+        zusd_mint: readonly,
+        szusd_mint: writable,
+        staking_vault: writable,
+        token_program: readonly,
+        system_program: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `RefreshPrice`
+    RefreshPrice {
+        authority: readonly,
+        global_config: writable,
+        szusd_market: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `CloseObligation`
+    CloseObligation {
+        owner: signer_writable,
+        authority: readonly,
+        market: writable,
+        obligation: writable,
+        owner_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+        trigger: writable,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `SetDelegate`
+    SetDelegate {
+        owner: signer,
+        obligation: writable,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `TransferObligation`
+    TransferObligation {
+        current_owner: signer_writable,
+        new_owner: signer,
+        obligation: writable,
+        trigger: writable,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `DepositAndBorrow`
+    DepositAndBorrow {
+        user: signer,
+        authority: readonly,
+        market: writable,
+        obligation: writable,
+        user_collateral: writable,
+        collateral_vault: writable,
+        user_zusd: writable,
+        zusd_mint: writable,
+        token_program: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `RepayAndWithdraw`
+    RepayAndWithdraw {
+        user: signer,
+        authority: readonly,
+        market: writable,
+        obligation: writable,
+        user_zusd: writable,
+        zusd_mint: writable,
+        user_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `GetObligationHealth`
+    GetObligationHealth {
+        market: readonly,
+        obligation: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `InitMarket`
+    InitMarket {
+        admin: signer_writable,
+        global_config: writable,
+        market: writable,
+        collateral_mint: readonly,
+        system_program: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `UpdateMarket`
+    UpdateMarket {
+        admin: signer,
+        global_config: readonly,
+        market: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `Redeem`
+    Redeem {
+        redeemer: signer,
+        authority: readonly,
+        global_config: readonly,
+        market: writable,
+        redeemer_zusd: writable,
+        zusd_mint: writable,
+        redeemer_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+        insurance_fund: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `Liquidate`, anyone can liquidate
+    Liquidate {
+        liquidator: signer,
+        authority: readonly,
+        global_config: writable,
+        market: writable,
+        obligation: writable,
+        collateral_market: writable,
+        staking_vault: writable,
+        zusd_mint: writable,
+        token_program: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `ClaimCollateralGains`
+    ClaimCollateralGains {
+        user: signer,
+        authority: readonly,
+        global_config: readonly,
+        staker: writable,
+        market: readonly,
+        user_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `StartAuction`, anyone can start an auction
+    StartAuction {
+        keeper: signer_writable,
+        market: writable,
+        obligation: writable,
+        collateral_market: readonly,
+        auction: writable,
+        system_program: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `BidAuction`
+    BidAuction {
+        bidder: signer_writable,
+        authority: readonly,
+        market: writable,
+        obligation: writable,
+        collateral_market: writable,
+        auction: writable,
+        bidder_zusd: writable,
+        zusd_mint: writable,
+        bidder_collateral: writable,
+        owner_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+        insurance_fund: writable,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `WriteOffBadDebt`, anyone can write off
+    WriteOffBadDebt {
+        caller: signer,
+        authority: readonly,
+        global_config: writable,
+        market: writable,
+        obligation: writable,
+        insurance: readonly,
+        insurance_fund: writable,
+        staking_vault: writable,
+        zusd_mint: writable,
+        token_program: readonly,
+        szusd_market: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `GlobalSettlement`, admin only
+    GlobalSettlement {
+        admin: signer,
+        global_config: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `SettleObligation`, anyone can settle
+    SettleObligation {
+        caller: signer,
+        global_config: writable,
+        market: writable,
+        obligation: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `RedeemSettlement`
+    RedeemSettlement {
+        holder: signer,
+        authority: readonly,
+        global_config: writable,
+        market: writable,
+        holder_zusd: writable,
+        zusd_mint: writable,
+        holder_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `ConfigurePsm`, admin only
+    ConfigurePsm {
+        admin: signer,
+        global_config: writable,
+        reserve_mint: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `PsmSwapIn`
+    PsmSwapIn {
+        user: signer,
+        authority: readonly,
+        global_config: writable,
+        user_reserve: writable,
+        psm_vault: writable,
+        user_zusd: writable,
+        zusd_mint: writable,
+        token_program: readonly,
+        insurance_fund: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `PsmSwapOut`
+    PsmSwapOut {
+        user: signer,
+        authority: readonly,
+        global_config: writable,
+        user_zusd: writable,
+        zusd_mint: writable,
+        user_reserve: writable,
+        psm_vault: writable,
+        token_program: readonly,
+        insurance_fund: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `FlashMint`. Flash mints only run as top-level instructions,
+    /// so there is no CPI helper.
+    FlashMint {
+        user: signer,
+        authority: readonly,
+        global_config: writable,
+        user_zusd: writable,
+        zusd_mint: writable,
+        token_program: readonly,
+        instructions_sysvar: readonly,
+        insurance_fund: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `FlashMintRepay`, a top-level instruction like `FlashMint`
+    FlashMintRepay {
+        user: signer,
+        global_config: writable,
+        user_zusd: writable,
+        zusd_mint: writable,
+        token_program: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `FlashBorrowZbtc`. Flash loans only run as top-level
+    /// instructions, so there is no CPI helper.
+    FlashBorrowZbtc {
+        user: signer,
+        authority: readonly,
+        global_config: writable,
+        market: writable,
+        user_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+        instructions_sysvar: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `FlashRepayZbtc`, a top-level instruction like `FlashBorrowZbtc`
+    FlashRepayZbtc {
+        user: signer,
+        market: writable,
+        user_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+        global_config: writable,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `Deleverage`
+    Deleverage {
+        user: signer,
+        authority: readonly,
+        global_config: writable,
+        market: writable,
+        obligation: writable,
+        collateral_market: writable,
+        staking_vault: writable,
+        zusd_mint: writable,
+        token_program: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `SetTrigger`
+    SetTrigger {
+        user: signer_writable,
+        obligation: readonly,
+        trigger: writable,
+        market: readonly,
+        system_program: readonly,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `CancelTrigger`
+    CancelTrigger {
+        user: signer_writable,
+        obligation: readonly,
+        trigger: writable,
+        global_config: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `ExecuteTrigger`, anyone can execute
+    ExecuteTrigger {
+        keeper: signer,
+        authority: readonly,
+        global_config: writable,
+        market: writable,
+        obligation: writable,
+        trigger: readonly,
+        collateral_market: writable,
+        staking_vault: writable,
+        zusd_mint: writable,
+        keeper_collateral: writable,
+        collateral_vault: writable,
+        token_program: readonly,
+    }
+}
+
+cpi_accounts! {
+    /// Accounts for `DepositSZUSD` and `WithdrawSZUSD`
+    PoolSzusd {
+        user: signer_writable,
+        authority: readonly,
+        global_config: writable,
+        user_szusd: writable,
+        szusd_mint: writable,
+        token_program: readonly,
+        system_program: readonly,
+        staker: writable,
+    }
+}
+
+/// Accounts of every instruction, indexed by its discriminant
+pub const INSTRUCTION_ACCOUNTS: &[(&str, &[AccountSpec])] = &[
+    ("Initialize", Initialize::ACCOUNTS),
+    ("InitObligation", InitObligation::ACCOUNTS),
+    ("DepositZBTC", DepositZbtc::ACCOUNTS),
+    ("WithdrawZBTC", WithdrawZbtc::ACCOUNTS),
+    ("BorrowZUSD", BorrowZusd::ACCOUNTS),
+    ("RepayZUSD", RepayZusd::ACCOUNTS),
+    ("Stake", Stake::ACCOUNTS),
+    ("RefreshPrice", RefreshPrice::ACCOUNTS),
+    ("Unstake", Stake::ACCOUNTS),
+    ("CloseObligation", CloseObligation::ACCOUNTS),
+    ("SetDelegate", SetDelegate::ACCOUNTS),
+    ("TransferObligation", TransferObligation::ACCOUNTS),
+    ("DepositAndBorrow", DepositAndBorrow::ACCOUNTS),
+    ("RepayAndWithdraw", RepayAndWithdraw::ACCOUNTS),
+    ("GetObligationHealth", GetObligationHealth::ACCOUNTS),
+    ("InitMarket", InitMarket::ACCOUNTS),
+    ("UpdateMarket", UpdateMarket::ACCOUNTS),
+    ("Redeem", Redeem::ACCOUNTS),
+    ("Liquidate", Liquidate::ACCOUNTS),
+    ("ClaimCollateralGains", ClaimCollateralGains::ACCOUNTS),
+    ("StartAuction", StartAuction::ACCOUNTS),
+    ("BidAuction", BidAuction::ACCOUNTS),
+    ("WriteOffBadDebt", WriteOffBadDebt::ACCOUNTS),
+    ("GlobalSettlement", GlobalSettlement::ACCOUNTS),
+    ("SettleObligation", SettleObligation::ACCOUNTS),
+    ("RedeemSettlement", RedeemSettlement::ACCOUNTS),
+    ("ConfigurePsm", ConfigurePsm::ACCOUNTS),
+    ("PsmSwapIn", PsmSwapIn::ACCOUNTS),
+    ("PsmSwapOut", PsmSwapOut::ACCOUNTS),
+    ("FlashMint", FlashMint::ACCOUNTS),
+    ("FlashMintRepay", FlashMintRepay::ACCOUNTS),
+    ("FlashBorrowZbtc", FlashBorrowZbtc::ACCOUNTS),
+    ("FlashRepayZbtc", FlashRepayZbtc::ACCOUNTS),
+    ("Deleverage", Deleverage::ACCOUNTS),
+    ("SetTrigger", SetTrigger::ACCOUNTS),
+    ("CancelTrigger", CancelTrigger::ACCOUNTS),
+    ("ExecuteTrigger", ExecuteTrigger::ACCOUNTS),
+    ("DepositSZUSD", PoolSzusd::ACCOUNTS),
+    ("WithdrawSZUSD", PoolSzusd::ACCOUNTS),
+];
+
+fn invoke_z_fubao<'info>(
+    program: &AccountInfo<'info>,
+    account_metas: Vec<AccountMeta>,
+    mut account_infos: Vec<AccountInfo<'info>>,
+    instruction: ZFubaoInstruction,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let instruction = Instruction {
+        program_id: *program.key,
+        accounts: account_metas,
+        data: instruction.pack(),
+    };
+    account_infos.push(program.clone());
+
+    invoke_signed(&instruction, &account_infos, signer_seeds)
+}
+
+// Instructions that check the LTV take the markets of the obligation's other
+// deposits after their fixed accounts, in deposit order
+fn with_deposit_markets<'info>(
+    mut account_metas: Vec<AccountMeta>,
+    mut account_infos: Vec<AccountInfo<'info>>,
+    deposit_markets: &[AccountInfo<'info>],
+) -> (Vec<AccountMeta>, Vec<AccountInfo<'info>>) {
+    for market in deposit_markets {
+        account_metas.push(AccountMeta::new_readonly(*market.key, false));
+        account_infos.push(market.clone());
+    }
+    (account_metas, account_infos)
+}
+
+pub fn initialize<'info>(
+    program: &AccountInfo<'info>,
+    accounts: Initialize<'_, 'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::Initialize,
+        signer_seeds,
+    )
+}
+
+pub fn init_obligation<'info>(
+    program: &AccountInfo<'info>,
+    accounts: InitObligation<'_, 'info>,
+    index: u16,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::InitObligation { index },
+        signer_seeds,
+    )
+}
+
+pub fn deposit_zbtc<'info>(
+    program: &AccountInfo<'info>,
+    accounts: DepositZbtc<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::DepositZBTC { amount },
+        signer_seeds,
+    )
+}
+
+pub fn withdraw_zbtc<'info>(
+    program: &AccountInfo<'info>,
+    accounts: WithdrawZbtc<'_, 'info>,
+    deposit_markets: &[AccountInfo<'info>],
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let (account_metas, account_infos) = with_deposit_markets(
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        deposit_markets,
+    );
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::WithdrawZBTC { amount },
+        signer_seeds,
+    )
+}
+
+pub fn borrow_zusd<'info>(
+    program: &AccountInfo<'info>,
+    accounts: BorrowZusd<'_, 'info>,
+    deposit_markets: &[AccountInfo<'info>],
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let (account_metas, account_infos) = with_deposit_markets(
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        deposit_markets,
+    );
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::BorrowZUSD { amount },
+        signer_seeds,
+    )
+}
+
+pub fn repay_zusd<'info>(
+    program: &AccountInfo<'info>,
+    accounts: RepayZusd<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::RepayZUSD { amount },
+        signer_seeds,
+    )
+}
+
+pub fn stake<'info>(
+    program: &AccountInfo<'info>,
+    accounts: Stake<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::Stake { amount },
+        signer_seeds,
+    )
+}
+
+pub fn unstake<'info>(
+    program: &AccountInfo<'info>,
+    accounts: Stake<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::Unstake { amount },
+        signer_seeds,
+    )
+}
+
+pub fn refresh_price<'info>(
+    program: &AccountInfo<'info>,
+    accounts: RefreshPrice<'_, 'info>,
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::RefreshPrice,
+        &[],
+    )
+}
+
+pub fn close_obligation<'info>(
+    program: &AccountInfo<'info>,
+    accounts: CloseObligation<'_, 'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::CloseObligation,
+        signer_seeds,
+    )
+}
+
+pub fn set_delegate<'info>(
+    program: &AccountInfo<'info>,
+    accounts: SetDelegate<'_, 'info>,
+    delegate: Pubkey,
+    permission: DelegatePermission,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::SetDelegate {
+            delegate,
+            permission,
+        },
+        signer_seeds,
+    )
+}
+
+pub fn transfer_obligation<'info>(
+    program: &AccountInfo<'info>,
+    accounts: TransferObligation<'_, 'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::TransferObligation,
+        signer_seeds,
+    )
+}
+
+pub fn deposit_and_borrow<'info>(
+    program: &AccountInfo<'info>,
+    accounts: DepositAndBorrow<'_, 'info>,
+    deposit_markets: &[AccountInfo<'info>],
+    deposit_amount: u64,
+    borrow_amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let (account_metas, account_infos) = with_deposit_markets(
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        deposit_markets,
+    );
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::DepositAndBorrow {
+            deposit_amount,
+            borrow_amount,
+        },
+        signer_seeds,
+    )
+}
+
+pub fn repay_and_withdraw<'info>(
+    program: &AccountInfo<'info>,
+    accounts: RepayAndWithdraw<'_, 'info>,
+    deposit_markets: &[AccountInfo<'info>],
+    repay_amount: u64,
+    withdraw_amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let (account_metas, account_infos) = with_deposit_markets(
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        deposit_markets,
+    );
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::RepayAndWithdraw {
+            repay_amount,
+            withdraw_amount,
+        },
+        signer_seeds,
+    )
+}
+
+/// Returns the health Z-Fubao computed for the obligation
+pub fn get_obligation_health<'info>(
+    program: &AccountInfo<'info>,
+    accounts: GetObligationHealth<'_, 'info>,
+    deposit_markets: &[AccountInfo<'info>],
+) -> Result<ObligationHealth, ProgramError> {
+    let (account_metas, account_infos) = with_deposit_markets(
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        deposit_markets,
+    );
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::GetObligationHealth,
+        &[],
+    )?;
+
+    // Only trust return data that Z-Fubao itself set
+    match get_return_data() {
+        Some((program_id, data)) if program_id == *program.key => {
+            Ok(ObligationHealth::try_from_slice(&data)?)
+        }
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+pub fn init_market<'info>(
+    program: &AccountInfo<'info>,
+    accounts: InitMarket<'_, 'info>,
+    ltv_ratio: u16,
+    price: u64,
+    deposit_cap: u64,
+    borrow_cap: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::InitMarket {
+            ltv_ratio,
+            price,
+            deposit_cap,
+            borrow_cap,
+        },
+        signer_seeds,
+    )
+}
+
+pub fn update_market<'info>(
+    program: &AccountInfo<'info>,
+    accounts: UpdateMarket<'_, 'info>,
+    ltv_ratio: u16,
+    price: u64,
+    deposit_cap: u64,
+    borrow_cap: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::UpdateMarket {
+            ltv_ratio,
+            price,
+            deposit_cap,
+            borrow_cap,
+        },
+        signer_seeds,
+    )
+}
+
+/// `obligations` are the obligations to redeem from, riskiest first
+pub fn redeem<'info>(
+    program: &AccountInfo<'info>,
+    accounts: Redeem<'_, 'info>,
+    obligations: &[AccountInfo<'info>],
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let mut account_metas = accounts.to_account_metas();
+    let mut account_infos = accounts.to_account_infos();
+    for obligation in obligations {
+        account_metas.push(AccountMeta::new(*obligation.key, false));
+        account_infos.push(obligation.clone());
+    }
+
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::Redeem { amount },
+        signer_seeds,
+    )
+}
+
+/// `szusd_market` is synced to the lowered exchange rate
+pub fn liquidate<'info>(
+    program: &AccountInfo<'info>,
+    accounts: Liquidate<'_, 'info>,
+    deposit_markets: &[AccountInfo<'info>],
+    szusd_market: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let (mut account_metas, mut account_infos) = with_deposit_markets(
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        deposit_markets,
+    );
+    account_metas.push(AccountMeta::new(*szusd_market.key, false));
+    account_infos.push(szusd_market.clone());
+
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::Liquidate,
+        signer_seeds,
+    )
+}
+
+pub fn claim_collateral_gains<'info>(
+    program: &AccountInfo<'info>,
+    accounts: ClaimCollateralGains<'_, 'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::ClaimCollateralGains,
+        signer_seeds,
+    )
+}
+
+pub fn start_auction<'info>(
+    program: &AccountInfo<'info>,
+    accounts: StartAuction<'_, 'info>,
+    deposit_markets: &[AccountInfo<'info>],
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let (account_metas, account_infos) = with_deposit_markets(
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        deposit_markets,
+    );
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::StartAuction,
+        signer_seeds,
+    )
+}
+
+pub fn bid_auction<'info>(
+    program: &AccountInfo<'info>,
+    accounts: BidAuction<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::BidAuction { amount },
+        signer_seeds,
+    )
+}
+
+pub fn write_off_bad_debt<'info>(
+    program: &AccountInfo<'info>,
+    accounts: WriteOffBadDebt<'_, 'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::WriteOffBadDebt,
+        signer_seeds,
+    )
+}
+
+/// `markets` are the markets to settle
+pub fn global_settlement<'info>(
+    program: &AccountInfo<'info>,
+    accounts: GlobalSettlement<'_, 'info>,
+    markets: &[AccountInfo<'info>],
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let mut account_metas = accounts.to_account_metas();
+    let mut account_infos = accounts.to_account_infos();
+    for market in markets {
+        account_metas.push(AccountMeta::new(*market.key, false));
+        account_infos.push(market.clone());
+    }
+
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::GlobalSettlement,
+        signer_seeds,
+    )
+}
+
+/// The deposit markets lose collateral to the settlement, so they are passed
+/// writable
+pub fn settle_obligation<'info>(
+    program: &AccountInfo<'info>,
+    accounts: SettleObligation<'_, 'info>,
+    deposit_markets: &[AccountInfo<'info>],
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let mut account_metas = accounts.to_account_metas();
+    let mut account_infos = accounts.to_account_infos();
+    for market in deposit_markets {
+        account_metas.push(AccountMeta::new(*market.key, false));
+        account_infos.push(market.clone());
+    }
+
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::SettleObligation,
+        signer_seeds,
+    )
+}
+
+pub fn redeem_settlement<'info>(
+    program: &AccountInfo<'info>,
+    accounts: RedeemSettlement<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::RedeemSettlement { amount },
+        signer_seeds,
+    )
+}
+
+pub fn configure_psm<'info>(
+    program: &AccountInfo<'info>,
+    accounts: ConfigurePsm<'_, 'info>,
+    fee_in_bps: u16,
+    fee_out_bps: u16,
+    ceiling: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::ConfigurePsm {
+            fee_in_bps,
+            fee_out_bps,
+            ceiling,
+        },
+        signer_seeds,
+    )
+}
+
+pub fn psm_swap_in<'info>(
+    program: &AccountInfo<'info>,
+    accounts: PsmSwapIn<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::PsmSwapIn { amount },
+        signer_seeds,
+    )
+}
+
+pub fn psm_swap_out<'info>(
+    program: &AccountInfo<'info>,
+    accounts: PsmSwapOut<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::PsmSwapOut { amount },
+        signer_seeds,
+    )
+}
+
+/// `szusd_market` is synced to the lowered exchange rate
+pub fn deleverage<'info>(
+    program: &AccountInfo<'info>,
+    accounts: Deleverage<'_, 'info>,
+    collateral_amount: u64,
+    deposit_markets: &[AccountInfo<'info>],
+    szusd_market: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let (mut account_metas, mut account_infos) = with_deposit_markets(
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        deposit_markets,
+    );
+    account_metas.push(AccountMeta::new(*szusd_market.key, false));
+    account_infos.push(szusd_market.clone());
+
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::Deleverage { collateral_amount },
+        signer_seeds,
+    )
+}
+
+pub fn set_trigger<'info>(
+    program: &AccountInfo<'info>,
+    accounts: SetTrigger<'_, 'info>,
+    threshold_bps: u16,
+    target_bps: u16,
+    keeper_tip: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::SetTrigger {
+            threshold_bps,
+            target_bps,
+            keeper_tip,
+        },
+        signer_seeds,
+    )
+}
+
+pub fn cancel_trigger<'info>(
+    program: &AccountInfo<'info>,
+    accounts: CancelTrigger<'_, 'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::CancelTrigger,
+        signer_seeds,
+    )
+}
+
+/// `szusd_market` is synced to the lowered exchange rate
+pub fn execute_trigger<'info>(
+    program: &AccountInfo<'info>,
+    accounts: ExecuteTrigger<'_, 'info>,
+    deposit_markets: &[AccountInfo<'info>],
+    szusd_market: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    let (mut account_metas, mut account_infos) = with_deposit_markets(
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        deposit_markets,
+    );
+    account_metas.push(AccountMeta::new(*szusd_market.key, false));
+    account_infos.push(szusd_market.clone());
+
+    invoke_z_fubao(
+        program,
+        account_metas,
+        account_infos,
+        ZFubaoInstruction::ExecuteTrigger,
+        signer_seeds,
+    )
+}
+
+pub fn deposit_szusd<'info>(
+    program: &AccountInfo<'info>,
+    accounts: PoolSzusd<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::DepositSZUSD { amount },
+        signer_seeds,
+    )
+}
+
+pub fn withdraw_szusd<'info>(
+    program: &AccountInfo<'info>,
+    accounts: PoolSzusd<'_, 'info>,
+    amount: u64,
+    signer_seeds: &[&[&[u8]]],
+) -> ProgramResult {
+    invoke_z_fubao(
+        program,
+        accounts.to_account_metas(),
+        accounts.to_account_infos(),
+        ZFubaoInstruction::WithdrawSZUSD { amount },
+        signer_seeds,
+    )
+}
@@ -0,0 +1,136 @@
+use solana_program::program_error::ProgramError;
+use thiserror::Error;
+
+// Returned as ProgramError::Custom(code), codes are stable once released
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZFubaoError {
+    #[error("LTV ratio exceeds the maximum for the collateral")]
+    InvalidLtvRatio = 0,
+    #[error("Signer is not allowed to operate this account")]
+    Unauthorized = 1,
+    #[error("Token account is not the protocol vault")]
+    InvalidVault = 2,
+    #[error("Mint does not match the market")]
+    InvalidMint = 3,
+    #[error("Obligation is undercollateralized")]
+    Undercollateralized = 4,
+    #[error("Debt would exceed the borrow limit")]
+    BorrowLimitExceeded = 5,
+    #[error("Withdrawal would leave too little collateral")]
+    WithdrawLimitExceeded = 6,
+    #[error("Repay amount exceeds the outstanding debt")]
+    RepayExceedsDebt = 7,
+    #[error("Obligation still has outstanding debt")]
+    OutstandingDebt = 8,
+    #[error("Obligation belongs to another market")]
+    MarketMismatch = 9,
+    #[error("Deposit would exceed the market deposit cap")]
+    DepositCapExceeded = 10,
+    #[error("Borrow would exceed the market borrow cap")]
+    BorrowCapExceeded = 11,
+    #[error("Obligation already holds collateral in the maximum number of markets")]
+    TooManyDeposits = 12,
+    #[error("Collateral in other markets has to be withdrawn first")]
+    OutstandingCollateral = 13,
+    #[error("Markets of the obligation deposits are missing or out of order")]
+    DepositMarketMismatch = 14,
+    #[error("Obligations must be redeemed from the riskiest redemption bucket up")]
+    ObligationsNotSorted = 15,
+    #[error("Obligation is not undercollateralized")]
+    ObligationHealthy = 16,
+    #[error("Stability pool has no ZUSD or stakers to absorb the debt")]
+    StabilityPoolEmpty = 17,
+    #[error("Stability pool already tracks the maximum number of markets")]
+    StabilityPoolFull = 18,
+    #[error("Collateral is already on auction")]
+    AuctionInProgress = 19,
+    #[error("Obligation has no debt to write off")]
+    NoBadDebt = 20,
+    #[error("Protocol is in global settlement")]
+    ProtocolSettled = 21,
+    #[error("Protocol is not in global settlement")]
+    ProtocolNotSettled = 22,
+    #[error("Market has no settled collateral to pay out")]
+    SettlementPoolEmpty = 23,
+    #[error("Swap would exceed the PSM ceiling")]
+    PsmCeilingExceeded = 24,
+    #[error("PSM reserves cannot cover the swap")]
+    InsufficientPsmReserves = 25,
+    #[error("Fee exceeds 100%")]
+    InvalidFee = 26,
+    #[error("Flash mint is not repaid by a later instruction of the transaction")]
+    FlashMintNotRepaid = 27,
+    #[error("A flash mint is already running")]
+    FlashMintInProgress = 28,
+    #[error("Repayment does not match the running flash mint")]
+    FlashMintRepayMismatch = 29,
+    #[error("Flash loan is not repaid by the next Z-Fubao instruction of the transaction")]
+    FlashLoanNotRepaid = 30,
+    #[error("Locked by a running flash loan")]
+    FlashLoanInProgress = 31,
+    #[error("Repayment does not match the running flash loan")]
+    FlashLoanRepayMismatch = 32,
+    #[error("Deleverage would cancel no debt")]
+    NothingToDeleverage = 33,
+    #[error("Trigger threshold must be at least 1 and below its target")]
+    InvalidTrigger = 34,
+    #[error("Obligation health is not below the trigger threshold")]
+    TriggerNotReached = 35,
+    #[error("Withdrawal exceeds the shares recorded for this staker")]
+    UnrecordedShares = 36,
+    #[error("Settlement redemptions open once every market's debt is settled")]
+    SettlementNotFinal = 37,
+    #[error("Deleverage would not improve the obligation's health")]
+    HealthNotImproved = 38,
+}
+
+impl ZFubaoError {
+    // Every variant, in code order, for clients and the IDL
+    pub const ALL: &'static [ZFubaoError] = &[
+        Self::InvalidLtvRatio,
+        Self::Unauthorized,
+        Self::InvalidVault,
+        Self::InvalidMint,
+        Self::Undercollateralized,
+        Self::BorrowLimitExceeded,
+        Self::WithdrawLimitExceeded,
+        Self::RepayExceedsDebt,
+        Self::OutstandingDebt,
+        Self::MarketMismatch,
+        Self::DepositCapExceeded,
+        Self::BorrowCapExceeded,
+        Self::TooManyDeposits,
+        Self::OutstandingCollateral,
+        Self::DepositMarketMismatch,
+        Self::ObligationsNotSorted,
+        Self::ObligationHealthy,
+        Self::StabilityPoolEmpty,
+        Self::StabilityPoolFull,
+        Self::AuctionInProgress,
+        Self::NoBadDebt,
+        Self::ProtocolSettled,
+        Self::ProtocolNotSettled,
+        Self::SettlementPoolEmpty,
+        Self::PsmCeilingExceeded,
+        Self::InsufficientPsmReserves,
+        Self::InvalidFee,
+        Self::FlashMintNotRepaid,
+        Self::FlashMintInProgress,
+        Self::FlashMintRepayMismatch,
+        Self::FlashLoanNotRepaid,
+        Self::FlashLoanInProgress,
+        Self::FlashLoanRepayMismatch,
+        Self::NothingToDeleverage,
+        Self::InvalidTrigger,
+        Self::TriggerNotReached,
+        Self::UnrecordedShares,
+        Self::SettlementNotFinal,
+        Self::HealthNotImproved,
+    ];
+}
+
+impl From<ZFubaoError> for ProgramError {
+    fn from(e: ZFubaoError) -> Self {
+        ProgramError::Custom(e as u32)
+    }
+}
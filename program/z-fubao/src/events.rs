@@ -0,0 +1,142 @@
+use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
+use solana_program::{log::sol_log_data, program_error::ProgramError, pubkey::Pubkey};
+
+// Each event is logged with sol_log_data as a single Borsh-encoded ZFubaoEvent,
+// so indexers can decode "Program data:" log lines with the IDL
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, PartialEq, Eq)]
+pub enum ZFubaoEvent {
+    CollateralDeposited {
+        obligation: Pubkey,
+        market: Pubkey,
+        payer: Pubkey,
+        amount: u64,
+    },
+    CollateralWithdrawn {
+        obligation: Pubkey,
+        market: Pubkey,
+        amount: u64,
+    },
+    ZusdBorrowed {
+        obligation: Pubkey,
+        amount: u64,
+    },
+    ZusdRepaid {
+        obligation: Pubkey,
+        payer: Pubkey,
+        amount: u64,
+    },
+    CollateralRedeemed {
+        obligation: Pubkey,
+        market: Pubkey,
+        redeemer: Pubkey,
+        zusd_amount: u64,
+        collateral_amount: u64,
+        fee: u64,
+        insurance_fee: u64,
+    },
+    ObligationLiquidated {
+        obligation: Pubkey,
+        market: Pubkey,
+        liquidator: Pubkey,
+        zusd_amount: u64,
+        collateral_amount: u64,
+    },
+    CollateralGainsClaimed {
+        staker: Pubkey,
+        market: Pubkey,
+        amount: u64,
+    },
+    AuctionStarted {
+        auction: Pubkey,
+        obligation: Pubkey,
+        market: Pubkey,
+        collateral_amount: u64,
+        start_price: u128,
+    },
+    AuctionBid {
+        auction: Pubkey,
+        bidder: Pubkey,
+        zusd_amount: u64,
+        collateral_amount: u64,
+        penalty: u64,
+    },
+    AuctionEnded {
+        auction: Pubkey,
+        obligation: Pubkey,
+        returned_amount: u64,
+        remaining_debt: u64,
+    },
+    BadDebtWrittenOff {
+        obligation: Pubkey,
+        insurance_amount: u64,
+        staker_amount: u64,
+        remaining_debt: u64,
+    },
+    MarketSettled {
+        market: Pubkey,
+        price: u64,
+    },
+    ObligationSettled {
+        obligation: Pubkey,
+        zusd_amount: u64,
+        collateral_value: u128,
+    },
+    SettlementRedeemed {
+        market: Pubkey,
+        holder: Pubkey,
+        zusd_amount: u64,
+        collateral_amount: u64,
+    },
+    PsmSwappedIn {
+        user: Pubkey,
+        reserve_amount: u64,
+        zusd_amount: u64,
+        fee: u64, // raw ZUSD
+    },
+    PsmSwappedOut {
+        user: Pubkey,
+        zusd_amount: u64,
+        reserve_amount: u64,
+        fee: u64, // raw reserve
+    },
+    FlashMinted {
+        user: Pubkey,
+        amount: u64,
+        fee: u64,
+        insurance_fee: u64,
+    },
+    FlashBorrowed {
+        user: Pubkey,
+        market: Pubkey,
+        amount: u64,
+        fee: u64,
+    },
+    ObligationDeleveraged {
+        obligation: Pubkey,
+        market: Pubkey,
+        zusd_amount: u64,
+        collateral_amount: u64,
+        fee: u64, // raw collateral
+    },
+    TriggerSet {
+        obligation: Pubkey,
+        market: Pubkey,
+        threshold_bps: u16,
+        target_bps: u16,
+        keeper_tip: u64,
+    },
+    TriggerExecuted {
+        obligation: Pubkey,
+        keeper: Pubkey,
+        zusd_amount: u64,
+        collateral_amount: u64,
+        keeper_tip: u64, // raw collateral
+    },
+}
+
+impl ZFubaoEvent {
+    pub fn emit(&self) -> Result<(), ProgramError> {
+        sol_log_data(&[&borsh::to_vec(self)?]);
+        Ok(())
+    }
+}
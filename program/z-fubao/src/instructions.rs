@@ -1,79 +1,107 @@
-use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::program_error::ProgramError;
+use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
 
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
+use crate::state::DelegatePermission;
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug)]
 pub enum ZFubaoInstruction {
-    /// Initialize a new vault
+    /// Initialize the protocol
+    ///
+    /// Creates the global config holding the ZUSD and SZUSD mints and the
+    /// staking state. The signer becomes the admin that creates and tunes
+    /// markets. Collateral markets are added afterwards with `InitMarket`.
     ///
     /// Accounts expected:
-    /// 0. `[signer]` The account of the person initializing the vault
-    /// 1. `[]` The authority account
+    /// 0. `[signer, writable]` The admin account
+    /// 1. `[writable]` The authority account
     /// 2. `[writable]` The global config account
-    /// 3. `[]` The ZBTC mint
-    /// 4. `[]` The ZUSD mint
+    /// 3. `[]` The ZUSD mint
+    /// 4. `[]` The SZUSD mint
     /// 5. `[]` System program
-    Initialize { ltv_ratio: u8, price: u64 },
+    Initialize,
 
     /// Initialize a new obligation for a user
     ///
+    /// A user can hold several obligations per market, one per position index.
+    ///
     /// Accounts expected:
     /// 0. `[signer]` The user account
     /// 1. `[]` Authority account
-    /// 2. `[writable]` The global config account
-    /// 3. `[writable]` The obligation account (PDA)
+    /// 2. `[]` The market account
+    /// 3. `[writable]` The obligation account (PDA of market, user and index)
     /// 4. `[]` The system program
-    InitObligation,
+    /// 5. `[]` The global config account
+    InitObligation { index: u16 },
 
-    /// Deposit ZBTC as collateral
+    /// Deposit collateral
+    ///
+    /// Anyone can deposit into any obligation, up to the market deposit cap.
+    /// The collateral can be of any market, an obligation holds deposits in up
+    /// to `MAX_DEPOSITS` markets.
     ///
     /// Accounts expected:
-    /// 0. `[signer]` The user account
+    /// 0. `[signer]` The payer account
     /// 1. `[]` Authority account
-    /// 2. `[writable]` The global config account
-    /// 3. `[writable]` The obligation account (PDA)
-    /// 4. `[writable]` User's ZBTC token account
-    /// 5. `[writable]` ZBTC vault token account
+    /// 2. `[writable]` The market of the collateral
+    /// 3. `[writable]` The beneficiary obligation account (PDA)
+    /// 4. `[writable]` Payer's collateral token account
+    /// 5. `[writable]` Collateral vault token account
     /// 6. `[]` Token program id
+    /// 7. `[]` The global config account
     DepositZBTC { amount: u64 },
 
-    /// Withdraw ZBTC collateral
+    /// Withdraw collateral
     ///
     /// Accounts expected:
-    /// 0. `[signer]` The user account
+    /// 0. `[signer]` The obligation owner, or a delegate with full permission
     /// 1. `[]` Authority account
-    /// 2. `[writable]` The global config account
+    /// 2. `[writable]` The market of the collateral
     /// 3. `[writable]` The obligation account (PDA)
-    /// 4. `[writable]` User's ZBTC token account
-    /// 5. `[writable]` ZBTC vault token account
+    /// 4. `[writable]` Collateral token account to receive the collateral (owned by the obligation owner when a delegate signs)
+    /// 5. `[writable]` Collateral vault token account
     /// 6. `[]` Token program id
+    /// 7. `[]` The global config account
+    /// 8. ..`8+N` `[]` The N markets of the obligation's other deposits, in deposit order
     WithdrawZBTC { amount: u64 },
 
-    /// Borrow ZUSD
+    /// Borrow ZUSD, up to the market borrow cap
+    ///
+    /// The borrow limit is the sum of every deposit's value weighted by the
+    /// LTV of its market. Nothing lends once the protocol is in global settlement.
     ///
     /// Accounts expected:
-    /// 0. `[signer]` The user account
+    /// 0. `[signer]` The obligation owner, or a delegate with full permission
     /// 1. `[]` Authority account
-    /// 2. `[writable]` The global config account
+    /// 2. `[writable]` The market account of the obligation
     /// 3. `[writable]` The obligation account (PDA)
-    /// 4. `[writable]` User's ZUSD token account
-    /// 5. `[]` ZUSD mint
+    /// 4. `[writable]` ZUSD token account to receive the loan (owned by the obligation owner when a delegate signs)
+    /// 5. `[writable]` ZUSD mint
     /// 6. `[]` Token program id
+    /// 7. `[]` The global config account
+    /// 8. ..`8+N` `[]` The N markets of the obligation's other deposits, in deposit order
     BorrowZUSD { amount: u64 },
 
     /// Repay ZUSD
     ///
+    /// Anyone can repay debt of any obligation.
+    ///
     /// Accounts expected:
-    /// 0. `[signer]` The user account
+    /// 0. `[signer]` The payer account
     /// 1. `[]` Authority account
-    /// 2. `[writable]` The global config account
-    /// 3. `[writable]` The obligation account (PDA)
-    /// 4. `[writable]` User's ZUSD token account
-    /// 5. `[]` ZUSD mint
+    /// 2. `[writable]` The market account of the obligation
+    /// 3. `[writable]` The beneficiary obligation account (PDA)
+    /// 4. `[writable]` Payer's ZUSD token account
+    /// 5. `[writable]` ZUSD mint
     /// 6. `[]` Token program id
+    /// 7. `[]` The global config account
     RepayZUSD { amount: u64 },
 
     /// Stake ZUSD tokens and mint SZUSD tokens
     ///
+    /// The SZUSD only earns the stability pool's collateral gains once it is
+    /// deposited with `DepositSZUSD`. Refused once the protocol is in global
+    /// settlement.
+    ///
     /// Accounts expected:
     /// 0. `[signer]` User account
     /// 1. `[]` Authority account
@@ -89,13 +117,22 @@ pub enum ZFubaoInstruction {
 
     /// Refresh price
     ///
+    /// Accrues the SZUSD price and syncs the price the SZUSD market values its
+    /// collateral at. Before SZUSD is listed the market PDA is still empty and
+    /// only the rate accrues. The price stays frozen once the
+    /// protocol is in global settlement.
+    ///
     /// Accounts expected:
     /// 0. `[]` Authority account
     /// 1. `[writable]` The global config account
+    /// 2. `[writable]` The SZUSD market (PDA of the SZUSD mint)
     RefreshPrice,
 
     /// Unstake SZUSD tokens and get back ZUSD tokens
     ///
+    /// Any SZUSD holder can unstake. SZUSD deposited into the stability pool
+    /// has to be withdrawn with `WithdrawSZUSD` first.
+    ///
     /// Accounts expected:
     /// 0. `[signer]` User's main account
     /// 1. `[]` Authority account
@@ -104,10 +141,591 @@ pub enum ZFubaoInstruction {
     /// 4. `[writable]` User's SZUSD token account
     /// 5. `[]` ZUSD mint
     /// 6. `[writable]` SZUSD mint
-    /// 5. `[writable]` Staking vault - where ZUSD is stored
-    /// 6. `[]` Token program
-    /// 7. `[]` System program
+    /// 7. `[writable]` Staking vault - where ZUSD is stored
+    /// 8. `[]` Token program
+    /// 9. `[]` System program
     Unstake { amount: u64 },
+
+    /// Close an obligation and reclaim its rent
+    ///
+    /// The obligation must have no outstanding ZUSD debt, no running auction and
+    /// collateral in at most one market. That collateral is returned to the user
+    /// before the account is closed, along with its trigger if it has one.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The obligation owner, receives the rent lamports
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The market of the remaining collateral
+    /// 3. `[writable]` The obligation account (PDA)
+    /// 4. `[writable]` User's collateral token account
+    /// 5. `[writable]` Collateral vault token account
+    /// 6. `[]` Token program id
+    /// 7. `[writable]` The trigger account (PDA of the obligation), may be uninitialized
+    /// 8. `[]` The global config account
+    CloseObligation,
+
+    /// Set or clear the delegate of an obligation
+    ///
+    /// Passing `DelegatePermission::None` removes the delegate.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The obligation owner
+    /// 1. `[writable]` The obligation account (PDA)
+    /// 2. `[]` The global config account
+    SetDelegate {
+        delegate: Pubkey,
+        permission: DelegatePermission,
+    },
+
+    /// Transfer an obligation, collateral and debt together, to a new owner
+    ///
+    /// The obligation keeps its address. Any delegate is cleared and any trigger
+    /// closed, its rent going back to the current owner. Refused while an
+    /// auction of its collateral is running.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The current obligation owner
+    /// 1. `[signer]` The new obligation owner
+    /// 2. `[writable]` The obligation account (PDA)
+    /// 3. `[writable]` The trigger account (PDA of the obligation), may be uninitialized
+    /// 4. `[]` The global config account
+    TransferObligation,
+
+    /// Deposit collateral and borrow ZUSD against the resulting position
+    ///
+    /// The collateral goes to the market of the obligation. The LTV check runs
+    /// once on the final state. Nothing lends once the protocol is in global
+    /// settlement.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The obligation owner, or a delegate with full permission
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The market account of the obligation
+    /// 3. `[writable]` The obligation account (PDA)
+    /// 4. `[writable]` User's collateral token account
+    /// 5. `[writable]` Collateral vault token account
+    /// 6. `[writable]` ZUSD token account to receive the loan (owned by the obligation owner when a delegate signs)
+    /// 7. `[writable]` ZUSD mint
+    /// 8. `[]` Token program id
+    /// 9. `[]` The global config account
+    /// 10. ..`10+N` `[]` The N markets of the obligation's other deposits, in deposit order
+    DepositAndBorrow {
+        deposit_amount: u64,
+        borrow_amount: u64,
+    },
+
+    /// Repay ZUSD and withdraw collateral from the resulting position
+    ///
+    /// The collateral comes from the market of the obligation. The LTV check
+    /// runs once on the final state.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The obligation owner, or a delegate with full permission
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The market account of the obligation
+    /// 3. `[writable]` The obligation account (PDA)
+    /// 4. `[writable]` User's ZUSD token account
+    /// 5. `[writable]` ZUSD mint
+    /// 6. `[writable]` Collateral token account to receive the collateral (owned by the obligation owner when a delegate signs)
+    /// 7. `[writable]` Collateral vault token account
+    /// 8. `[]` Token program id
+    /// 9. `[]` The global config account
+    /// 10. ..`10+N` `[]` The N markets of the obligation's other deposits, in deposit order
+    RepayAndWithdraw {
+        repay_amount: u64,
+        withdraw_amount: u64,
+    },
+
+    /// Compute the health of an obligation without changing any state
+    ///
+    /// The result is published as a Borsh `ObligationHealth` via return data,
+    /// so it can be read by simulating the instruction or after a CPI. Its
+    /// max_withdrawable and liquidation_price refer to the queried market.
+    ///
+    /// Accounts expected:
+    /// 0. `[]` The queried market
+    /// 1. `[]` The obligation account (PDA)
+    /// 2. `[]` The global config account
+    /// 3. ..`3+N` `[]` The N markets of the obligation's other deposits, in deposit order
+    GetObligationHealth,
+
+    /// Open a collateral market
+    ///
+    /// The market is a PDA of its collateral mint with its own price, LTV and
+    /// caps, and lends the ZUSD of the global config. The collateral vault is
+    /// the authority's associated token account for the collateral mint.
+    ///
+    /// A SZUSD market ignores `price` and values SZUSD at the staking exchange
+    /// rate instead. Its LTV is capped at `MAX_SZUSD_LTV_RATIO`. No market opens
+    /// once the protocol is in global settlement.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The admin account
+    /// 1. `[writable]` The global config account
+    /// 2. `[writable]` The market account (PDA of the collateral mint)
+    /// 3. `[]` The collateral mint
+    /// 4. `[]` System program
+    InitMarket {
+        ltv_ratio: u16,
+        price: u64,
+        deposit_cap: u64,
+        borrow_cap: u64,
+    },
+
+    /// Update the price, LTV and caps of a market
+    ///
+    /// Caps below the current totals only block new deposits or borrows. The
+    /// price of a SZUSD market only moves with `RefreshPrice`. Settled markets
+    /// cannot be updated.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The admin account
+    /// 1. `[]` The global config account
+    /// 2. `[writable]` The market account
+    UpdateMarket {
+        ltv_ratio: u16,
+        price: u64,
+        deposit_cap: u64,
+        borrow_cap: u64,
+    },
+
+    /// Redeem ZUSD for collateral at face value
+    ///
+    /// Spends up to `amount` ZUSD and pays out collateral of the market worth the
+    /// same at the market price, less `REDEMPTION_FEE_BPS`. The fee stays in the
+    /// redeemed obligations, except for `INSURANCE_FEE_SHARE_BPS` of it that the
+    /// redeemer buys at face value, paying that ZUSD into the insurance fund. The
+    /// rest of the ZUSD is burned. Debt is taken from the passed obligations in
+    /// order, each losing collateral worth the debt it sheds. Anyone can redeem,
+    /// which puts a floor under the ZUSD price.
+    ///
+    /// The obligations must live in the market and come riskiest first. The
+    /// market counts its obligations per redemption bucket, by their collateral
+    /// ratio in the market at its opening price (see `REDEMPTION_BUCKETS`), and
+    /// each passed obligation has to be in the lowest bucket still holding any.
+    /// Obligations without debt or collateral in the market are skipped, and
+    /// the redemption ends at the first one too little is left to redeem from.
+    /// Redemptions stop once the protocol is settled, `RedeemSettlement` takes
+    /// over.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The redeemer
+    /// 1. `[]` Authority account
+    /// 2. `[]` The global config account
+    /// 3. `[writable]` The market of the collateral
+    /// 4. `[writable]` Redeemer's ZUSD token account
+    /// 5. `[writable]` ZUSD mint
+    /// 6. `[writable]` Collateral token account to receive the collateral
+    /// 7. `[writable]` Collateral vault token account
+    /// 8. `[]` Token program id
+    /// 9. `[writable]` Insurance fund (ZUSD token account of the insurance PDA)
+    /// 10. ..`10+N` `[writable]` The N obligations to redeem from
+    Redeem { amount: u64 },
+
+    /// Liquidate an undercollateralized obligation into the stability pool
+    ///
+    /// ZUSD in the staking vault cancels the debt and the collateral worth it,
+    /// plus `LIQUIDATION_BONUS_BPS`, goes to the stakers pro-rata to their
+    /// recorded shares. The collateral stays in the market's vault until
+    /// claimed. The SZUSD exchange rate drops by the share of the vault burned.
+    /// Liquidates as much as the collateral in the market, the debt and the
+    /// vault allow. Anyone can liquidate.
+    ///
+    /// The SZUSD market, passed after the deposit markets, is synced to the
+    /// lowered exchange rate.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The liquidator
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` The market account of the obligation
+    /// 4. `[writable]` The obligation account (PDA)
+    /// 5. `[writable]` The market of the collateral to seize, may be the market of the obligation
+    /// 6. `[writable]` Staking vault - where ZUSD is stored
+    /// 7. `[writable]` ZUSD mint
+    /// 8. `[]` Token program id
+    /// 9. ..`9+N` `[]` The N markets of the obligation's other deposits, in deposit order,
+    ///    followed by the `[writable]` SZUSD market (PDA of the SZUSD mint)
+    Liquidate,
+
+    /// Claim the collateral the stability pool earned a staker in one market
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The staker
+    /// 1. `[]` Authority account
+    /// 2. `[]` The global config account
+    /// 3. `[writable]` The staker account (PDA of the staker)
+    /// 4. `[]` The market of the collateral
+    /// 5. `[writable]` Collateral token account to receive the gains
+    /// 6. `[writable]` Collateral vault token account
+    /// 7. `[]` Token program id
+    ClaimCollateralGains,
+
+    /// Put an undercollateralized obligation's collateral in one market on auction
+    ///
+    /// The whole deposit moves out of the obligation into the auction account.
+    /// Its price starts at `AUCTION_START_PRICE_BPS` of the oracle price and
+    /// falls to `AUCTION_FLOOR_PRICE_BPS` of it over `AUCTION_DURATION` seconds.
+    /// One auction per obligation and market runs at a time. Anyone can start
+    /// one and pays its rent.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The keeper starting the auction
+    /// 1. `[writable]` The market account of the obligation
+    /// 2. `[writable]` The obligation account (PDA)
+    /// 3. `[]` The market of the collateral to auction, may be the market of the obligation
+    /// 4. `[writable]` The auction account (PDA of the obligation and the collateral market)
+    /// 5. `[]` System program
+    /// 6. `[]` The global config account
+    /// 7. ..`7+N` `[]` The N markets of the obligation's other deposits, in deposit order
+    StartAuction,
+
+    /// Buy auctioned collateral with ZUSD at the current auction price
+    ///
+    /// Pays up to `amount` ZUSD, never more than the obligation owes plus
+    /// `AUCTION_PENALTY_BPS` or than the collateral left is worth. The penalty
+    /// goes to the insurance fund and the rest is burned to cancel the debt.
+    /// The bid that covers the debt or takes the last collateral ends the
+    /// auction: the leftover collateral goes to the owner and the rent to the
+    /// bidder.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The bidder
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The market account of the obligation
+    /// 3. `[writable]` The obligation account (PDA)
+    /// 4. `[writable]` The market of the auctioned collateral, may be the market of the obligation
+    /// 5. `[writable]` The auction account (PDA)
+    /// 6. `[writable]` Bidder's ZUSD token account
+    /// 7. `[writable]` ZUSD mint
+    /// 8. `[writable]` Bidder's collateral token account
+    /// 9. `[writable]` The auction owner's collateral token account, receives the leftovers
+    /// 10. `[writable]` Collateral vault token account
+    /// 11. `[]` Token program id
+    /// 12. `[writable]` The insurance fund, the ZUSD token account of the insurance PDA
+    /// 13. `[]` The global config account
+    BidAuction { amount: u64 },
+
+    /// Write off the debt an obligation is left with once all its collateral is gone
+    ///
+    /// The insurance fund burns ZUSD for the debt first. Whatever it cannot
+    /// cover is burned from the staking vault, lowering the SZUSD exchange rate.
+    /// Debt beyond both stays on the obligation. Needs an obligation without
+    /// deposits or running auctions. Anyone can write off.
+    ///
+    /// The SZUSD market is synced to the lowered exchange rate.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The caller
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` The market account of the obligation
+    /// 4. `[writable]` The obligation account (PDA)
+    /// 5. `[]` The insurance PDA
+    /// 6. `[writable]` The insurance fund, the ZUSD token account of the insurance PDA
+    /// 7. `[writable]` Staking vault - where ZUSD is stored
+    /// 8. `[writable]` ZUSD mint
+    /// 9. `[]` Token program id
+    /// 10. `[writable]` The SZUSD market (PDA of the SZUSD mint)
+    WriteOffBadDebt,
+
+    /// Wind the protocol down for good, e.g. after an oracle or bridge failure
+    ///
+    /// The first call starts the settlement: the SZUSD price accrues one last
+    /// time, after which staking, `RefreshPrice` and `InitMarket` are refused.
+    /// Every passed market is settled: its price is frozen and it stops lending.
+    /// Call again with any market left out.
+    ///
+    /// Once every obligation is settled, a call passing every market fixes the
+    /// settlement rate and opens `RedeemSettlement`.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The admin account
+    /// 1. `[writable]` The global config account
+    /// 2. ..`2+N` `[writable]` The N markets to settle
+    GlobalSettlement,
+
+    /// Settle an obligation's debt against its collateral at the frozen prices
+    ///
+    /// Collateral worth the debt is seized, market by market starting with the
+    /// market of the obligation, into each market's settled collateral. The
+    /// debt is cancelled and backs the ZUSD that `RedeemSettlement` pays out.
+    /// What is left of the collateral can be withdrawn as usual. Needs every
+    /// market of the obligation settled and no running auction. Anyone can
+    /// settle.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The caller
+    /// 1. `[writable]` The global config account
+    /// 2. `[writable]` The market account of the obligation
+    /// 3. `[writable]` The obligation account (PDA)
+    /// 4. ..`4+N` `[writable]` The N markets of the obligation's other deposits, in deposit order
+    SettleObligation,
+
+    /// Redeem ZUSD for settled collateral of one market at its frozen price
+    ///
+    /// Each ZUSD pays out its pro-rata share of the collateral value seized by
+    /// `SettleObligation`, never more than $1. Burns up to `amount` ZUSD, less
+    /// when the market runs out of settled collateral. Refused until
+    /// `GlobalSettlement` fixed the rate, so every holder gets the same share.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The ZUSD holder
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` The market of the collateral
+    /// 4. `[writable]` Holder's ZUSD token account
+    /// 5. `[writable]` ZUSD mint
+    /// 6. `[writable]` Collateral token account to receive the collateral
+    /// 7. `[writable]` Collateral vault token account
+    /// 8. `[]` Token program id
+    RedeemSettlement { amount: u64 },
+
+    /// Set the reserve stablecoin, fees and ceiling of the peg stability module
+    ///
+    /// Fees are in basis points of the swapped amount. Lowering the ceiling
+    /// below the reserves only blocks swaps in. The reserve mint can only
+    /// change while the PSM holds no reserves. The PSM vault is the authority's
+    /// associated token account for the reserve mint.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The admin account
+    /// 1. `[writable]` The global config account
+    /// 2. `[]` The reserve mint
+    ConfigurePsm {
+        fee_in_bps: u16,
+        fee_out_bps: u16,
+        ceiling: u64,
+    },
+
+    /// Swap the reserve stablecoin for ZUSD 1:1, less the PSM fee in
+    ///
+    /// The fee is minted to the insurance fund, so every reserve unit backs
+    /// minted ZUSD. Refused when the reserves would exceed the PSM ceiling or
+    /// once the protocol is in global settlement.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The user account
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` User's reserve token account
+    /// 4. `[writable]` PSM vault token account
+    /// 5. `[writable]` ZUSD token account to receive the ZUSD
+    /// 6. `[writable]` ZUSD mint
+    /// 7. `[]` Token program id
+    /// 8. `[writable]` Insurance fund (ZUSD token account of the insurance PDA)
+    PsmSwapIn { amount: u64 },
+
+    /// Swap ZUSD for the reserve stablecoin 1:1, less the PSM fee out
+    ///
+    /// Takes `amount` ZUSD. The fee goes to the insurance fund and the rest is
+    /// burned for the same amount of the reserve, so fees never count against
+    /// the ceiling.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The user account
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` User's ZUSD token account
+    /// 4. `[writable]` ZUSD mint
+    /// 5. `[writable]` Reserve token account to receive the reserve
+    /// 6. `[writable]` PSM vault token account
+    /// 7. `[]` Token program id
+    /// 8. `[writable]` Insurance fund (ZUSD token account of the insurance PDA)
+    PsmSwapOut { amount: u64 },
+
+    /// Mint ZUSD that has to be burned again, plus `FLASH_MINT_FEE_BPS`, within
+    /// the same transaction
+    ///
+    /// `INSURANCE_FEE_SHARE_BPS` of the fee is minted to the insurance fund, so
+    /// the burned fee only takes the rest out of circulation.
+    ///
+    /// A `FlashMintRepay` for the principal plus fee has to follow later in the
+    /// transaction, found through the instructions sysvar. Both have to be
+    /// top-level instructions of the transaction, so flash mints cannot be
+    /// nested or invoked by CPI. Refused once the protocol is in global
+    /// settlement.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The user account
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` ZUSD token account to receive the ZUSD
+    /// 4. `[writable]` ZUSD mint
+    /// 5. `[]` Token program id
+    /// 6. `[]` Instructions sysvar
+    /// 7. `[writable]` Insurance fund (ZUSD token account of the insurance PDA)
+    FlashMint { amount: u64 },
+
+    /// Burn the principal plus fee of the running flash mint
+    ///
+    /// `amount` has to be exactly what the flash mint is due.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The user account
+    /// 1. `[writable]` The global config account
+    /// 2. `[writable]` User's ZUSD token account
+    /// 3. `[writable]` ZUSD mint
+    /// 4. `[]` Token program id
+    FlashMintRepay { amount: u64 },
+
+    /// Borrow collateral out of a market's vault, to be returned plus
+    /// `FLASH_LOAN_FEE_BPS` within the same transaction
+    ///
+    /// The next Z-Fubao instruction of the transaction has to be the
+    /// `FlashRepayZbtc` of the principal plus fee for the same market, found
+    /// through the instructions sysvar. Both have to be top-level instructions
+    /// of the transaction, so flash loans cannot be invoked by CPI. Until the
+    /// repayment the global config is locked, so no Z-Fubao instruction runs,
+    /// not even one invoked by CPI from another program. The fee goes to the
+    /// stability pool, stakers claim it as collateral gains. Without stakers it
+    /// stays in the vault.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The user account
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` The market of the collateral
+    /// 4. `[writable]` Collateral token account to receive the loan
+    /// 5. `[writable]` Collateral vault token account
+    /// 6. `[]` Token program id
+    /// 7. `[]` Instructions sysvar
+    FlashBorrowZbtc { amount: u64 },
+
+    /// Return the principal plus fee of a market's running flash loan
+    ///
+    /// `amount` has to be exactly what the flash loan is due.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The user account
+    /// 1. `[writable]` The market of the collateral
+    /// 2. `[writable]` User's collateral token account
+    /// 3. `[writable]` Collateral vault token account
+    /// 4. `[]` Token program id
+    /// 5. `[writable]` The global config account
+    FlashRepayZbtc { amount: u64 },
+
+    /// Sell up to `collateral_amount` of one deposit to the stability pool to
+    /// pay down the obligation's debt
+    ///
+    /// The pool buys the collateral at the oracle price less
+    /// `DELEVERAGE_FEE_BPS` and burns ZUSD out of the staking vault to cancel as
+    /// much debt. Like in a liquidation the collateral stays in the market's
+    /// vault for the stakers to claim and the SZUSD exchange rate drops by the
+    /// share of the vault burned, but there is no bonus and the obligation does
+    /// not have to be undercollateralized. Sells no more than the deposit, the
+    /// debt and the vault allow. Refused unless the health factor improves or
+    /// the debt is cleared, so it never stands in for a liquidation. Needs full
+    /// permission, it moves collateral out of the obligation.
+    ///
+    /// The SZUSD market, passed after the deposit markets, is synced to the
+    /// lowered exchange rate.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The owner or a delegate with full permission
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` The market account of the obligation
+    /// 4. `[writable]` The obligation account (PDA)
+    /// 5. `[writable]` The market of the collateral to sell, may be the market of the obligation
+    /// 6. `[writable]` Staking vault - where ZUSD is stored
+    /// 7. `[writable]` ZUSD mint
+    /// 8. `[]` Token program id
+    /// 9. ..`9+N` `[]` The N markets of the obligation's other deposits, in deposit order,
+    ///    followed by the `[writable]` SZUSD market (PDA of the SZUSD mint)
+    Deleverage { collateral_amount: u64 },
+
+    /// Create or replace the deleverage trigger of an obligation
+    ///
+    /// Once the health factor drops below `threshold_bps`, any keeper can run
+    /// `ExecuteTrigger` to sell collateral of the market to the stability pool
+    /// like `Deleverage` does, until the health factor is back at `target_bps`.
+    /// Each execution pays the keeper `keeper_tip` raw collateral out of the
+    /// obligation's deposit in that market, scaled down when the stability pool
+    /// only covers part of the sale. The trigger stays armed until cancelled.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The owner or a delegate with full permission, pays the rent
+    /// 1. `[]` The obligation account (PDA)
+    /// 2. `[writable]` The trigger account (PDA)
+    /// 3. `[]` The market of the collateral to sell
+    /// 4. `[]` System program
+    /// 5. `[]` The global config account
+    SetTrigger {
+        threshold_bps: u16,
+        target_bps: u16,
+        keeper_tip: u64,
+    },
+
+    /// Remove the deleverage trigger of an obligation, returning its rent
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` The owner or a delegate with full permission
+    /// 1. `[]` The obligation account (PDA)
+    /// 2. `[writable]` The trigger account (PDA)
+    /// 3. `[]` The global config account
+    CancelTrigger,
+
+    /// Deleverage an obligation whose health factor is below its trigger's
+    /// threshold, at the current market prices. Anyone can execute.
+    ///
+    /// The keeper gets the tip first, then the stability pool buys just enough
+    /// collateral at the `Deleverage` price to bring the health factor back to
+    /// the target. Less when the deposit, the debt or the vault run out.
+    ///
+    /// The SZUSD market, passed after the deposit markets, is synced to the
+    /// lowered exchange rate.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer]` The keeper
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` The market account of the obligation
+    /// 4. `[writable]` The obligation account (PDA)
+    /// 5. `[]` The trigger account (PDA)
+    /// 6. `[writable]` The market of the trigger, may be the market of the obligation
+    /// 7. `[writable]` Staking vault - where ZUSD is stored
+    /// 8. `[writable]` ZUSD mint
+    /// 9. `[writable]` Keeper's collateral token account for the tip
+    /// 10. `[writable]` Collateral vault token account of the trigger's market
+    /// 11. `[]` Token program id
+    /// 12. ..`12+N` `[]` The N markets of the obligation's other deposits, in deposit order,
+    ///     followed by the `[writable]` SZUSD market (PDA of the SZUSD mint)
+    ExecuteTrigger,
+
+    /// Deposit SZUSD into the stability pool
+    ///
+    /// The SZUSD is burned and recorded as shares in the user's staker account,
+    /// created on the first deposit. Only these shares earn the pool's
+    /// collateral gains, SZUSD held in wallets or vaults earns none. Refused
+    /// once the protocol is in global settlement.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` User account
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` User's SZUSD token account
+    /// 4. `[writable]` SZUSD mint
+    /// 5. `[]` Token program
+    /// 6. `[]` System program
+    /// 7. `[writable]` The user's staker account (PDA of the user)
+    DepositSZUSD { amount: u64 },
+
+    /// Withdraw SZUSD from the stability pool
+    ///
+    /// Drops the shares from the user's staker account and mints the SZUSD back.
+    /// Gains earned so far stay claimable.
+    ///
+    /// Accounts expected:
+    /// 0. `[signer, writable]` User account
+    /// 1. `[]` Authority account
+    /// 2. `[writable]` The global config account
+    /// 3. `[writable]` User's SZUSD token account
+    /// 4. `[writable]` SZUSD mint
+    /// 5. `[]` Token program
+    /// 6. `[]` System program
+    /// 7. `[writable]` The user's staker account (PDA of the user)
+    WithdrawSZUSD { amount: u64 },
 }
 
 impl ZFubaoInstruction {
@@ -121,13 +739,12 @@ impl ZFubaoInstruction {
     pub fn pack(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(9);
         match self {
-            Self::Initialize { ltv_ratio, price } => {
+            Self::Initialize => {
                 buf.extend_from_slice(&[0]);
-                buf.extend_from_slice(&ltv_ratio.to_le_bytes());
-                buf.extend_from_slice(&price.to_le_bytes());
             }
-            Self::InitObligation => {
+            Self::InitObligation { index } => {
                 buf.extend_from_slice(&[1]);
+                buf.extend_from_slice(&index.to_le_bytes());
             }
             Self::DepositZBTC { amount } => {
                 buf.extend_from_slice(&[2]);
@@ -156,6 +773,155 @@ impl ZFubaoInstruction {
                 buf.extend_from_slice(&[8]);
                 buf.extend_from_slice(&amount.to_le_bytes());
             }
+            Self::CloseObligation => {
+                buf.extend_from_slice(&[9]);
+            }
+            Self::SetDelegate {
+                delegate,
+                permission,
+            } => {
+                buf.extend_from_slice(&[10]);
+                buf.extend_from_slice(delegate.as_ref());
+                buf.extend_from_slice(&[*permission as u8]);
+            }
+            Self::TransferObligation => {
+                buf.extend_from_slice(&[11]);
+            }
+            Self::DepositAndBorrow {
+                deposit_amount,
+                borrow_amount,
+            } => {
+                buf.extend_from_slice(&[12]);
+                buf.extend_from_slice(&deposit_amount.to_le_bytes());
+                buf.extend_from_slice(&borrow_amount.to_le_bytes());
+            }
+            Self::RepayAndWithdraw {
+                repay_amount,
+                withdraw_amount,
+            } => {
+                buf.extend_from_slice(&[13]);
+                buf.extend_from_slice(&repay_amount.to_le_bytes());
+                buf.extend_from_slice(&withdraw_amount.to_le_bytes());
+            }
+            Self::GetObligationHealth => {
+                buf.extend_from_slice(&[14]);
+            }
+            Self::InitMarket {
+                ltv_ratio,
+                price,
+                deposit_cap,
+                borrow_cap,
+            } => {
+                buf.extend_from_slice(&[15]);
+                buf.extend_from_slice(&ltv_ratio.to_le_bytes());
+                buf.extend_from_slice(&price.to_le_bytes());
+                buf.extend_from_slice(&deposit_cap.to_le_bytes());
+                buf.extend_from_slice(&borrow_cap.to_le_bytes());
+            }
+            Self::UpdateMarket {
+                ltv_ratio,
+                price,
+                deposit_cap,
+                borrow_cap,
+            } => {
+                buf.extend_from_slice(&[16]);
+                buf.extend_from_slice(&ltv_ratio.to_le_bytes());
+                buf.extend_from_slice(&price.to_le_bytes());
+                buf.extend_from_slice(&deposit_cap.to_le_bytes());
+                buf.extend_from_slice(&borrow_cap.to_le_bytes());
+            }
+            Self::Redeem { amount } => {
+                buf.extend_from_slice(&[17]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::Liquidate => {
+                buf.extend_from_slice(&[18]);
+            }
+            Self::ClaimCollateralGains => {
+                buf.extend_from_slice(&[19]);
+            }
+            Self::StartAuction => {
+                buf.extend_from_slice(&[20]);
+            }
+            Self::BidAuction { amount } => {
+                buf.extend_from_slice(&[21]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::WriteOffBadDebt => {
+                buf.extend_from_slice(&[22]);
+            }
+            Self::GlobalSettlement => {
+                buf.extend_from_slice(&[23]);
+            }
+            Self::SettleObligation => {
+                buf.extend_from_slice(&[24]);
+            }
+            Self::RedeemSettlement { amount } => {
+                buf.extend_from_slice(&[25]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::ConfigurePsm {
+                fee_in_bps,
+                fee_out_bps,
+                ceiling,
+            } => {
+                buf.extend_from_slice(&[26]);
+                buf.extend_from_slice(&fee_in_bps.to_le_bytes());
+                buf.extend_from_slice(&fee_out_bps.to_le_bytes());
+                buf.extend_from_slice(&ceiling.to_le_bytes());
+            }
+            Self::PsmSwapIn { amount } => {
+                buf.extend_from_slice(&[27]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::PsmSwapOut { amount } => {
+                buf.extend_from_slice(&[28]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::FlashMint { amount } => {
+                buf.extend_from_slice(&[29]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::FlashMintRepay { amount } => {
+                buf.extend_from_slice(&[30]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::FlashBorrowZbtc { amount } => {
+                buf.extend_from_slice(&[31]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::FlashRepayZbtc { amount } => {
+                buf.extend_from_slice(&[32]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::Deleverage { collateral_amount } => {
+                buf.extend_from_slice(&[33]);
+                buf.extend_from_slice(&collateral_amount.to_le_bytes());
+            }
+            Self::SetTrigger {
+                threshold_bps,
+                target_bps,
+                keeper_tip,
+            } => {
+                buf.extend_from_slice(&[34]);
+                buf.extend_from_slice(&threshold_bps.to_le_bytes());
+                buf.extend_from_slice(&target_bps.to_le_bytes());
+                buf.extend_from_slice(&keeper_tip.to_le_bytes());
+            }
+            Self::CancelTrigger => {
+                buf.extend_from_slice(&[35]);
+            }
+            Self::ExecuteTrigger => {
+                buf.extend_from_slice(&[36]);
+            }
+            Self::DepositSZUSD { amount } => {
+                buf.extend_from_slice(&[37]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::WithdrawSZUSD { amount } => {
+                buf.extend_from_slice(&[38]);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
         }
         buf
     }
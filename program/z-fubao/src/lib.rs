@@ -1,5 +1,10 @@
 #![allow(unexpected_cfgs)]
+pub mod cpi;
+#[cfg(not(feature = "no-entrypoint"))]
 pub mod entrypoint;
+pub mod error;
+pub mod events;
 pub mod instructions;
+pub mod math;
 pub mod processor;
 pub mod state;
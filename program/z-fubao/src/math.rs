@@ -0,0 +1,313 @@
+use std::fmt;
+
+use solana_program::program_error::ProgramError;
+
+// U192 leaves room for a WAD-scaled product of two u64-sized values
+mod uint_types {
+    #![allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+
+    use uint::construct_uint;
+
+    construct_uint! {
+        pub struct U192(3);
+    }
+}
+pub use uint_types::U192;
+
+// Number of decimal places a `Decimal` keeps
+pub const WAD_DECIMALS: u8 = 18;
+// Scale of a `Decimal`, 1.0 == WAD
+pub const WAD: u64 = 1_000_000_000_000_000_000;
+// Basis points in 1.0, used for ratios stored in config
+pub const BPS_SCALER: u64 = 10_000;
+
+// Which way to round when precision has to be dropped.
+//
+// Amounts credited to users (borrow capacity, withdrawable collateral, minted
+// shares, redemptions) round down. Amounts owed by users (required collateral,
+// debt) round up, so rounding never leaks value out of the protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+// Unsigned fixed-point number with 18 decimal places
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Decimal(U192);
+
+impl Decimal {
+    pub fn zero() -> Self {
+        Self(U192::zero())
+    }
+
+    pub fn one() -> Self {
+        Self(Self::wad())
+    }
+
+    fn wad() -> U192 {
+        U192::from(WAD)
+    }
+
+    // Wraps a value that is already WAD-scaled
+    pub fn from_scaled_val(scaled_val: u128) -> Self {
+        Self(U192::from(scaled_val))
+    }
+
+    // The WAD-scaled value, as exposed to clients
+    pub fn to_scaled_val(self) -> Result<u128, ProgramError> {
+        if self.0 > U192::from(u128::MAX) {
+            return Err(ProgramError::ArithmeticOverflow);
+        }
+        Ok(self.0.as_u128())
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Self(U192::from(value) * Self::wad())
+    }
+
+    // Ratio expressed in basis points, e.g. 7_500 -> 0.75
+    pub fn from_bps(bps: u64) -> Self {
+        Self(U192::from(bps) * Self::wad() / U192::from(BPS_SCALER))
+    }
+
+    // Whole-token value of a raw token amount, e.g. 150_000_000 with 8 decimals -> 1.5
+    pub fn from_token_amount(amount: u64, decimals: u8) -> Result<Self, ProgramError> {
+        let scaled = U192::from(amount) * Self::wad();
+        Ok(Self(scaled / Self::ten_pow(decimals)?))
+    }
+
+    // Raw token amount for a whole-token value, rounded in the given direction
+    pub fn to_token_amount(self, decimals: u8, rounding: Rounding) -> Result<u64, ProgramError> {
+        let scaled = self
+            .0
+            .checked_mul(Self::ten_pow(decimals)?)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        Self::u192_to_u64(Self::div_rounded(scaled, Self::wad(), rounding)?)
+    }
+
+    // Integer part of the value, rounded in the given direction
+    pub fn to_u64(self, rounding: Rounding) -> Result<u64, ProgramError> {
+        Self::u192_to_u64(Self::div_rounded(self.0, Self::wad(), rounding)?)
+    }
+
+    pub fn try_add(self, rhs: Self) -> Result<Self, ProgramError> {
+        self.0
+            .checked_add(rhs.0)
+            .map(Self)
+            .ok_or(ProgramError::ArithmeticOverflow)
+    }
+
+    pub fn try_sub(self, rhs: Self) -> Result<Self, ProgramError> {
+        self.0
+            .checked_sub(rhs.0)
+            .map(Self)
+            .ok_or(ProgramError::ArithmeticOverflow)
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+
+    pub fn try_mul(self, rhs: Self, rounding: Rounding) -> Result<Self, ProgramError> {
+        let product = self
+            .0
+            .checked_mul(rhs.0)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        Self::div_rounded(product, Self::wad(), rounding).map(Self)
+    }
+
+    pub fn try_div(self, rhs: Self, rounding: Rounding) -> Result<Self, ProgramError> {
+        let scaled = self
+            .0
+            .checked_mul(Self::wad())
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        Self::div_rounded(scaled, rhs.0, rounding).map(Self)
+    }
+
+    // 8 * log2 of the value rounded down, with 1.0 at 0. Good enough to sort
+    // values into buckets about 9% wide. None for zero.
+    pub fn log2_eighths(self) -> Option<i32> {
+        if self.is_zero() {
+            return None;
+        }
+
+        // Divide by WAD first so the rounding happens once, on the real value.
+        // The 64 bit shift keeps the fraction for values below one.
+        let shift = if self.0.bits() <= 128 { 64 } else { 0 };
+        Some(Self::raw_log2_eighths((self.0 << shift) / Self::wad()) - shift * 8)
+    }
+
+    // Integer part from the highest bit, then three fractional bits by squaring
+    // the mantissa
+    fn raw_log2_eighths(value: U192) -> i32 {
+        let exponent = value.bits() - 1;
+        // Mantissa in [1, 2) with 63 fractional bits
+        let mut mantissa = if exponent >= 63 {
+            (value >> (exponent - 63)).low_u128()
+        } else {
+            (value << (63 - exponent)).low_u128()
+        };
+
+        let mut eighths = exponent as i32 * 8;
+        for bit in [4, 2, 1] {
+            mantissa = (mantissa * mantissa) >> 63;
+            if mantissa >= 1 << 64 {
+                mantissa >>= 1;
+                eighths += bit;
+            }
+        }
+        eighths
+    }
+
+    fn div_rounded(
+        numerator: U192,
+        denominator: U192,
+        rounding: Rounding,
+    ) -> Result<U192, ProgramError> {
+        if denominator.is_zero() {
+            return Err(ProgramError::ArithmeticOverflow);
+        }
+
+        let (quotient, remainder) = numerator.div_mod(denominator);
+        match rounding {
+            Rounding::Up if !remainder.is_zero() => Ok(quotient + U192::one()),
+            _ => Ok(quotient),
+        }
+    }
+
+    fn ten_pow(exponent: u8) -> Result<U192, ProgramError> {
+        // Token decimals are a u8, but anything past 10^38 is not a real mint
+        if exponent > 38 {
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(U192::exp10(exponent as usize))
+    }
+
+    fn u192_to_u64(value: U192) -> Result<u64, ProgramError> {
+        if value > U192::from(u64::MAX) {
+            return Err(ProgramError::ArithmeticOverflow);
+        }
+        Ok(value.as_u64())
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (integer, fraction) = self.0.div_mod(Self::wad());
+        write!(
+            f,
+            "{}.{:0width$}",
+            integer,
+            fraction.as_u64(),
+            width = WAD_DECIMALS as usize
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decimal_math() {
+        // Testing Scenario:
+        // 1. Token amounts convert to and from whole-token values with mint decimals
+        // 2. Rounding goes down for user credit and up for what the user owes
+        // 3. Results that do not fit fail with ArithmeticOverflow
+        // 4. Decimals past 38 are refused
+        let one_zbtc = Decimal::from_token_amount(1_000_000_000, 9).unwrap();
+        assert_eq!(one_zbtc, Decimal::one());
+        assert_eq!(one_zbtc.to_token_amount(6, Rounding::Down), Ok(1_000_000));
+        assert_eq!(
+            Decimal::from_token_amount(150_000_000, 8)
+                .unwrap()
+                .to_string(),
+            "1.500000000000000000"
+        );
+        assert_eq!(Decimal::from_bps(7_500).to_string(), "0.750000000000000000");
+
+        // Rounding of products, the smallest unit times itself
+        let smallest = Decimal::from_scaled_val(1);
+        assert_eq!(
+            smallest.try_mul(smallest, Rounding::Down),
+            Ok(Decimal::zero())
+        );
+        assert_eq!(smallest.try_mul(smallest, Rounding::Up), Ok(smallest));
+        assert_eq!(
+            Decimal::from_u64(3).try_mul(Decimal::from_bps(5_000), Rounding::Up),
+            Ok(Decimal::from_bps(15_000))
+        );
+
+        // Rounding of quotients, 1/3
+        let third = Decimal::one()
+            .try_div(Decimal::from_u64(3), Rounding::Down)
+            .unwrap();
+        assert_eq!(third.to_scaled_val(), Ok(333_333_333_333_333_333));
+        assert_eq!(
+            Decimal::one().try_div(Decimal::from_u64(3), Rounding::Up),
+            Ok(Decimal::from_scaled_val(333_333_333_333_333_334))
+        );
+        assert_eq!(
+            Decimal::from_u64(6).try_div(Decimal::from_u64(3), Rounding::Up),
+            Ok(Decimal::from_u64(2))
+        );
+
+        // Rounding of token amounts
+        assert_eq!(third.to_token_amount(6, Rounding::Down), Ok(333_333));
+        assert_eq!(third.to_token_amount(6, Rounding::Up), Ok(333_334));
+        assert_eq!(one_zbtc.to_token_amount(6, Rounding::Up), Ok(1_000_000));
+        assert_eq!(third.to_u64(Rounding::Down), Ok(0));
+        assert_eq!(third.to_u64(Rounding::Up), Ok(1));
+
+        // Overflows
+        let max = Decimal::from_u64(u64::MAX);
+        assert_eq!(
+            max.try_mul(max, Rounding::Down)
+                .and_then(|squared| squared.try_mul(max, Rounding::Down)),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+        assert_eq!(
+            max.try_mul(max, Rounding::Down)
+                .and_then(|squared| squared.try_div(Decimal::one(), Rounding::Down)),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+        assert_eq!(
+            Decimal::one().try_div(Decimal::zero(), Rounding::Down),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+        assert_eq!(
+            Decimal::zero().try_sub(Decimal::one()),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+        assert_eq!(
+            max.try_add(Decimal::one())
+                .and_then(|value| value.to_u64(Rounding::Down)),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+        assert_eq!(
+            max.to_token_amount(1, Rounding::Down),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+        assert_eq!(
+            max.try_mul(max, Rounding::Down)
+                .and_then(|squared| squared.to_scaled_val()),
+            Err(ProgramError::ArithmeticOverflow)
+        );
+
+        // 10^38 is the largest power a mint can ask for
+        assert!(Decimal::from_token_amount(1, 38).is_ok());
+        assert_eq!(
+            Decimal::from_token_amount(1, 39),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert_eq!(
+            Decimal::one().to_token_amount(39, Rounding::Down),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+}
@@ -3,21 +3,37 @@ use solana_program::{
     account_info::{AccountInfo, next_account_info},
     clock::Clock,
     entrypoint::ProgramResult,
+    instruction::{TRANSACTION_LEVEL_STACK_HEIGHT, get_stack_height},
     msg,
-    program::{invoke, invoke_signed},
+    program::{invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
+    program_pack::Pack,
     pubkey::Pubkey,
     rent::Rent,
-    system_instruction,
-    sysvar::Sysvar,
+    system_instruction, system_program,
+    sysvar::{
+        Sysvar,
+        instructions::{load_current_index_checked, load_instruction_at_checked},
+    },
 };
-use std::ops::Div;
+use spl_associated_token_account::get_associated_token_address;
 
 use crate::{
+    error::ZFubaoError,
+    events::ZFubaoEvent,
     instructions::ZFubaoInstruction,
+    math::{BPS_SCALER, Decimal, Rounding},
     state::{
-        AUTHORITY_SEED, GLOBAL_CONFIG_SEED, OBLIGATION_SEED, Obligation, ZFubaoConfig,
-        find_obligation_pda,
+        AUCTION_FLOOR_PRICE_BPS, AUCTION_PENALTY_BPS, AUCTION_SEED, AUCTION_START_PRICE_BPS,
+        AUTHORITY_SEED, Auction, AuctionFill, CollateralDeposit, CollateralGain, CollateralState,
+        DELEVERAGE_FEE_BPS, DelegatePermission, Deleveraging, FLASH_LOAN_FEE_BPS,
+        FLASH_MINT_FEE_BPS, GLOBAL_CONFIG_SEED, INSURANCE_FEE_SHARE_BPS, INSURANCE_SEED,
+        LIQUIDATION_BONUS_BPS, Liquidation, MARKET_SEED, MAX_DEPOSITS, MAX_POOL_MARKETS, Market,
+        NO_REDEMPTION_BUCKET, OBLIGATION_SEED, Obligation, ObligationHealth, PoolMarket,
+        PositionStatus, PriceSource, PsmSwap, REDEMPTION_BUCKETS, REDEMPTION_FEE_BPS, Redemption,
+        STAKER_SEED, SettlementRedemption, Staker, TRIGGER_SEED, Trigger, ZFubaoConfig,
+        find_auction_pda, find_insurance_pda, find_market_pda, find_obligation_pda,
+        find_staker_pda, find_trigger_pda,
     },
 };
 
@@ -32,13 +48,13 @@ impl Processor {
         let instruction = ZFubaoInstruction::unpack(instruction_data)?;
 
         match instruction {
-            ZFubaoInstruction::Initialize { ltv_ratio, price } => {
+            ZFubaoInstruction::Initialize => {
                 msg!("Instruction: Initialize");
-                Self::process_initialize(program_id, accounts, ltv_ratio, price)
+                Self::process_initialize(program_id, accounts)
             }
-            ZFubaoInstruction::InitObligation => {
+            ZFubaoInstruction::InitObligation { index } => {
                 msg!("Instruction: InitObligation");
-                Self::process_init_obligation(program_id, accounts)
+                Self::process_init_obligation(program_id, accounts, index)
             }
             ZFubaoInstruction::DepositZBTC { amount } => {
                 msg!("Instruction: DepositZBTC");
@@ -68,26 +84,198 @@ impl Processor {
                 msg!("Instruction: Unstake");
                 Self::process_unstake(program_id, accounts, amount)
             }
+            ZFubaoInstruction::CloseObligation => {
+                msg!("Instruction: CloseObligation");
+                Self::process_close_obligation(program_id, accounts)
+            }
+            ZFubaoInstruction::SetDelegate {
+                delegate,
+                permission,
+            } => {
+                msg!("Instruction: SetDelegate");
+                Self::process_set_delegate(program_id, accounts, delegate, permission)
+            }
+            ZFubaoInstruction::TransferObligation => {
+                msg!("Instruction: TransferObligation");
+                Self::process_transfer_obligation(program_id, accounts)
+            }
+            ZFubaoInstruction::DepositAndBorrow {
+                deposit_amount,
+                borrow_amount,
+            } => {
+                msg!("Instruction: DepositAndBorrow");
+                Self::process_deposit_and_borrow(
+                    program_id,
+                    accounts,
+                    deposit_amount,
+                    borrow_amount,
+                )
+            }
+            ZFubaoInstruction::RepayAndWithdraw {
+                repay_amount,
+                withdraw_amount,
+            } => {
+                msg!("Instruction: RepayAndWithdraw");
+                Self::process_repay_and_withdraw(
+                    program_id,
+                    accounts,
+                    repay_amount,
+                    withdraw_amount,
+                )
+            }
+            ZFubaoInstruction::GetObligationHealth => {
+                msg!("Instruction: GetObligationHealth");
+                Self::process_get_obligation_health(program_id, accounts)
+            }
+            ZFubaoInstruction::InitMarket {
+                ltv_ratio,
+                price,
+                deposit_cap,
+                borrow_cap,
+            } => {
+                msg!("Instruction: InitMarket");
+                Self::process_init_market(
+                    program_id,
+                    accounts,
+                    ltv_ratio,
+                    price,
+                    deposit_cap,
+                    borrow_cap,
+                )
+            }
+            ZFubaoInstruction::UpdateMarket {
+                ltv_ratio,
+                price,
+                deposit_cap,
+                borrow_cap,
+            } => {
+                msg!("Instruction: UpdateMarket");
+                Self::process_update_market(
+                    program_id,
+                    accounts,
+                    ltv_ratio,
+                    price,
+                    deposit_cap,
+                    borrow_cap,
+                )
+            }
+            ZFubaoInstruction::Redeem { amount } => {
+                msg!("Instruction: Redeem");
+                Self::process_redeem(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::Liquidate => {
+                msg!("Instruction: Liquidate");
+                Self::process_liquidate(program_id, accounts)
+            }
+            ZFubaoInstruction::ClaimCollateralGains => {
+                msg!("Instruction: ClaimCollateralGains");
+                Self::process_claim_collateral_gains(program_id, accounts)
+            }
+            ZFubaoInstruction::StartAuction => {
+                msg!("Instruction: StartAuction");
+                Self::process_start_auction(program_id, accounts)
+            }
+            ZFubaoInstruction::BidAuction { amount } => {
+                msg!("Instruction: BidAuction");
+                Self::process_bid_auction(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::WriteOffBadDebt => {
+                msg!("Instruction: WriteOffBadDebt");
+                Self::process_write_off_bad_debt(program_id, accounts)
+            }
+            ZFubaoInstruction::GlobalSettlement => {
+                msg!("Instruction: GlobalSettlement");
+                Self::process_global_settlement(program_id, accounts)
+            }
+            ZFubaoInstruction::SettleObligation => {
+                msg!("Instruction: SettleObligation");
+                Self::process_settle_obligation(program_id, accounts)
+            }
+            ZFubaoInstruction::RedeemSettlement { amount } => {
+                msg!("Instruction: RedeemSettlement");
+                Self::process_redeem_settlement(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::ConfigurePsm {
+                fee_in_bps,
+                fee_out_bps,
+                ceiling,
+            } => {
+                msg!("Instruction: ConfigurePsm");
+                Self::process_configure_psm(program_id, accounts, fee_in_bps, fee_out_bps, ceiling)
+            }
+            ZFubaoInstruction::PsmSwapIn { amount } => {
+                msg!("Instruction: PsmSwapIn");
+                Self::process_psm_swap_in(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::PsmSwapOut { amount } => {
+                msg!("Instruction: PsmSwapOut");
+                Self::process_psm_swap_out(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::FlashMint { amount } => {
+                msg!("Instruction: FlashMint");
+                Self::process_flash_mint(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::FlashMintRepay { amount } => {
+                msg!("Instruction: FlashMintRepay");
+                Self::process_flash_mint_repay(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::FlashBorrowZbtc { amount } => {
+                msg!("Instruction: FlashBorrowZbtc");
+                Self::process_flash_borrow_zbtc(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::FlashRepayZbtc { amount } => {
+                msg!("Instruction: FlashRepayZbtc");
+                Self::process_flash_repay_zbtc(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::Deleverage { collateral_amount } => {
+                msg!("Instruction: Deleverage");
+                Self::process_deleverage(program_id, accounts, collateral_amount)
+            }
+            ZFubaoInstruction::SetTrigger {
+                threshold_bps,
+                target_bps,
+                keeper_tip,
+            } => {
+                msg!("Instruction: SetTrigger");
+                Self::process_set_trigger(
+                    program_id,
+                    accounts,
+                    threshold_bps,
+                    target_bps,
+                    keeper_tip,
+                )
+            }
+            ZFubaoInstruction::CancelTrigger => {
+                msg!("Instruction: CancelTrigger");
+                Self::process_cancel_trigger(program_id, accounts)
+            }
+            ZFubaoInstruction::ExecuteTrigger => {
+                msg!("Instruction: ExecuteTrigger");
+                Self::process_execute_trigger(program_id, accounts)
+            }
+            ZFubaoInstruction::DepositSZUSD { amount } => {
+                msg!("Instruction: DepositSZUSD");
+                Self::process_deposit_szusd(program_id, accounts, amount)
+            }
+            ZFubaoInstruction::WithdrawSZUSD { amount } => {
+                msg!("Instruction: WithdrawSZUSD");
+                Self::process_withdraw_szusd(program_id, accounts, amount)
+            }
         }
     }
 
-    fn process_initialize(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        ltv_ratio: u8,
-        price: u64,
-    ) -> ProgramResult {
+    fn process_initialize(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
-        let owner = next_account_info(account_info_iter)?;
+        let admin = next_account_info(account_info_iter)?;
         let authority_account = next_account_info(account_info_iter)?;
         let global_config_acount = next_account_info(account_info_iter)?;
-        let zbtc_mint = next_account_info(account_info_iter)?;
         let zusd_mint = next_account_info(account_info_iter)?;
-        let system_program = next_account_info(account_info_iter)?;
+        let szusd_mint = next_account_info(account_info_iter)?;
+        let _system_program = next_account_info(account_info_iter)?;
 
         // Check signer
-        if !owner.is_signer {
+        if !admin.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
@@ -103,6 +291,11 @@ impl Processor {
             return Err(ProgramError::InvalidAccountData);
         }
 
+        // The config names the admin, so it can only be written once
+        if !global_config_acount.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
         let rent = Rent::get()?;
 
         if authority_account.data_is_empty() {
@@ -112,77 +305,111 @@ impl Processor {
             let lamports = rent.minimum_balance(space);
             invoke_signed(
                 &system_instruction::create_account(
-                    &owner.key,
-                    &authority_account.key,
+                    admin.key,
+                    authority_account.key,
                     lamports,
                     space as u64,
                     program_id,
                 ),
-                &[owner.clone(), authority_account.clone()],
+                &[admin.clone(), authority_account.clone()],
                 &[&[AUTHORITY_SEED, &[authority_bump]]],
             )?;
         }
 
-        // Check program ownership
-        if global_config_acount.data_is_empty() {
-            msg!("Create global config account");
+        msg!("Create global config account");
 
-            let space = ZFubaoConfig::LEN;
-            let lamports = rent.minimum_balance(space);
+        let space = ZFubaoConfig::LEN;
+        let lamports = rent.minimum_balance(space);
 
-            invoke_signed(
-                &system_instruction::create_account(
-                    &owner.key,
-                    &global_config_acount.key,
-                    lamports,
-                    space as u64,
-                    program_id,
-                ),
-                &[owner.clone(), global_config_acount.clone()],
-                &[&[GLOBAL_CONFIG_SEED, &[global_config_bump]]],
-            )?;
+        invoke_signed(
+            &system_instruction::create_account(
+                admin.key,
+                global_config_acount.key,
+                lamports,
+                space as u64,
+                program_id,
+            ),
+            &[admin.clone(), global_config_acount.clone()],
+            &[&[GLOBAL_CONFIG_SEED, &[global_config_bump]]],
+        )?;
+
+        // Value math is normalized with the decimals of the actual mint
+        let zusd_decimals = Self::load_mint(zusd_mint)?.decimals;
+
+        // Staking shares are priced in ZUSD, so they need a mint of their own
+        Self::load_mint(szusd_mint)?;
+        if szusd_mint.key == zusd_mint.key {
+            return Err(ZFubaoError::InvalidMint.into());
         }
 
-        // Initialize lending state data
         let zfubao_config = ZFubaoConfig {
+            admin: *admin.key,
             authority: *authority_account.key,
 
-            zbtc_mint: *zbtc_mint.key,
             zusd_mint: *zusd_mint.key,
-
-            ltv_ratio,
-            price,
+            zusd_decimals,
+            szusd_mint: *szusd_mint.key,
 
             authority_bump,
             global_config_bump,
 
             start_time: Clock::get()?.unix_timestamp,
             szusd_price_ratio: 10000,
+
+            total_staked_shares: 0,
+            stability_pool: [PoolMarket::default(); MAX_POOL_MARKETS],
+
+            settled_at: 0,
+            settlement_debt: 0,
+            settlement_value: 0,
+            market_count: 0,
+            settlement_final: false,
+
+            psm_mint: Pubkey::default(),
+            psm_decimals: 0,
+            psm_fee_in_bps: 0,
+            psm_fee_out_bps: 0,
+            psm_ceiling: 0,
+            psm_reserves: 0,
+
+            flash_mint_due: 0,
+
+            flash_loan_market: Pubkey::default(),
         };
 
-        zfubao_config.serialize(&mut *global_config_acount.data.borrow_mut())?;
+        zfubao_config.serialize(&mut &mut global_config_acount.data.borrow_mut()[..])?;
 
-        msg!("Lending state initialized");
+        msg!("Global config initialized");
         Ok(())
     }
 
-    fn process_init_obligation(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    fn process_init_obligation(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        index: u16,
+    ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
         let user = next_account_info(account_info_iter)?;
-        let authority_account = next_account_info(account_info_iter)?;
-        let global_config_account = next_account_info(account_info_iter)?;
+        let _authority_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
         let obligation_account = next_account_info(account_info_iter)?;
         let system_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
 
         // Check signer
         if !user.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
+
+        // Obligations can only be opened in an existing market
+        Self::load_market(program_id, market_account)?;
+
         // Derive PDA for obligation
-        let (pda, bump_seed) =
-            Pubkey::find_program_address(&[OBLIGATION_SEED, user.key.as_ref()], program_id);
+        let (pda, bump_seed) = find_obligation_pda(market_account.key, user.key, index, program_id);
 
         // Verify obligation account is the PDA
         if *obligation_account.key != pda {
@@ -197,36 +424,44 @@ impl Processor {
         if obligation_account.owner != program_id {
             msg!("Create obligation account");
 
-            let rent = Rent::get()?;
-            let space = Obligation::LEN;
-            let lamports = rent.minimum_balance(space);
-
-            invoke_signed(
-                &system_instruction::create_account(
-                    user.key,
-                    obligation_account.key,
-                    lamports,
-                    space as u64,
-                    program_id,
-                ),
+            Self::create_pda_account(
+                user,
+                obligation_account,
+                system_program,
+                program_id,
+                Obligation::LEN,
                 &[
-                    user.clone(),
-                    obligation_account.clone(),
-                    system_program.clone(),
+                    OBLIGATION_SEED,
+                    market_account.key.as_ref(),
+                    user.key.as_ref(),
+                    &index.to_le_bytes(),
+                    &[bump_seed],
                 ],
-                &[&[OBLIGATION_SEED, user.key.as_ref(), &[bump_seed]]],
             )?;
         }
 
         // Initialize obligation data
         let obligation = Obligation {
-            zbtc_deposit: 0,
+            owner: *user.key,
+            creator: *user.key,
+            index,
+            market: *market_account.key,
+            delegate: Pubkey::default(),
+            delegate_permission: DelegatePermission::None,
+            deposits: [CollateralDeposit::default(); MAX_DEPOSITS],
             zusd_borrowed: 0,
+            auctions: 0,
+            redemption_bucket: NO_REDEMPTION_BUCKET,
         };
 
-        obligation.serialize(&mut *obligation_account.data.borrow_mut())?;
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
 
-        msg!("Obligation initialized for user {}", user.key);
+        msg!(
+            "Obligation {} initialized for user {} in market {}",
+            index,
+            user.key,
+            market_account.key
+        );
         Ok(())
     }
 
@@ -237,59 +472,75 @@ impl Processor {
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
-        let user = next_account_info(account_info_iter)?;
-        let authority_account = next_account_info(account_info_iter)?;
-        let global_config_account = next_account_info(account_info_iter)?;
+        let payer = next_account_info(account_info_iter)?;
+        let _authority_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
         let obligation_account = next_account_info(account_info_iter)?;
-        let user_zbtc_account = next_account_info(account_info_iter)?;
+        let payer_zbtc_account = next_account_info(account_info_iter)?;
         let vault_zbtc_account = next_account_info(account_info_iter)?;
         let token_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
 
         // Check signer
-        if !user.is_signer {
+        if !payer.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        if find_obligation_pda(&user.key, program_id).0 != *obligation_account.key {
-            return Err(ProgramError::InvalidAccountData);
-        }
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
 
-        // Load obligation data
-        let mut obligation = Obligation::try_from_slice(&obligation_account.data.borrow())?;
+        // Load beneficiary obligation data, anyone can top it up
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
 
-        // Verify obligation account
-        if obligation_account.owner != program_id {
-            return Err(ProgramError::InvalidAccountData);
-        }
+        // Collateral can go to any market, the obligation tracks it per market
+        let mut market = Self::load_market(program_id, market_account)?;
 
-        // Transfer ZBTC from user to vault
+        // Collateral only counts if it actually lands in the market's vault
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
+        market.add_deposits(amount)?;
+
+        // Transfer ZBTC from payer to vault
         invoke(
             &spl_token::instruction::transfer(
                 token_program.key,
-                user_zbtc_account.key,
+                payer_zbtc_account.key,
                 vault_zbtc_account.key,
-                user.key,
+                payer.key,
                 &[],
                 amount,
             )?,
             &[
-                user_zbtc_account.clone(),
+                payer_zbtc_account.clone(),
                 vault_zbtc_account.clone(),
-                user.clone(),
+                payer.clone(),
                 token_program.clone(),
             ],
         )?;
 
         // Update obligation state
-        obligation.zbtc_deposit = obligation
-            .zbtc_deposit
-            .checked_add(amount)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        obligation.add_deposit(market_account.key, amount)?;
 
-        // Save updated obligation data
-        obligation.serialize(&mut *obligation_account.data.borrow_mut())?;
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+
+        // Save updated obligation and market data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::CollateralDeposited {
+            obligation: *obligation_account.key,
+            market: *market_account.key,
+            payer: *payer.key,
+            amount,
+        }
+        .emit()?;
 
-        msg!("Deposited {} ZBTC", amount);
+        msg!(
+            "Deposited {} collateral of market {} into obligation of {}",
+            amount,
+            market_account.key,
+            obligation.owner
+        );
         Ok(())
     }
 
@@ -302,42 +553,56 @@ impl Processor {
 
         let user = next_account_info(account_info_iter)?;
         let authority_account = next_account_info(account_info_iter)?;
-        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
         let obligation_account = next_account_info(account_info_iter)?;
         let user_zbtc_account = next_account_info(account_info_iter)?;
         let vault_zbtc_account = next_account_info(account_info_iter)?;
         let token_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
 
         // Check signer
         if !user.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        if find_obligation_pda(&user.key, program_id).0 != *obligation_account.key {
-            return Err(ProgramError::InvalidAccountData);
-        }
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
 
         // Load obligation data
-        let mut obligation = Obligation::try_from_slice(&obligation_account.data.borrow())?;
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
 
-        // Verify obligation account
-        if obligation_account.owner != program_id {
-            return Err(ProgramError::InvalidAccountData);
+        if !obligation.can_be_operated_by(user.key, DelegatePermission::Full) {
+            return Err(ZFubaoError::Unauthorized.into());
         }
 
-        // Load lending state
-        let global_config = ZFubaoConfig::try_from_slice(&global_config_account.data.borrow())?;
-
-        // Verify lending state account
-        if global_config_account.owner != program_id {
-            return Err(ProgramError::InvalidAccountData);
+        // Proceeds go to the owner when a delegate operates the obligation
+        if *user.key != obligation.owner {
+            Self::check_token_account_owner(user_zbtc_account, &obligation.owner)?;
         }
 
+        // Load the market to withdraw collateral from
+        let mut market = Self::load_market(program_id, market_account)?;
+
+        // The authority owns every market's vault, so pay out of this market's only
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
         // Check if withdrawal would make the position under-collateralized
-        let max_withdrawable = Self::calculate_max_withdrawable(&obligation, &global_config)?;
+        let markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            account_info_iter.as_slice(),
+        )?;
+        let position = Self::calculate_position_status(&obligation, &markets)?;
 
-        if amount > max_withdrawable {
-            return Err(ProgramError::InvalidArgument);
+        if position.is_undercollateralized() {
+            msg!("Obligation is undercollateralized, nothing can be withdrawn");
+            return Err(ZFubaoError::Undercollateralized.into());
+        }
+
+        if amount > position.max_withdrawable {
+            return Err(ZFubaoError::WithdrawLimitExceeded.into());
         }
 
         // Transfer ZBTC from vault to user
@@ -346,7 +611,7 @@ impl Processor {
                 token_program.key,
                 vault_zbtc_account.key,
                 user_zbtc_account.key,
-                &authority_account.key,
+                authority_account.key,
                 &[],
                 amount,
             )?,
@@ -356,19 +621,31 @@ impl Processor {
                 authority_account.clone(),
                 token_program.clone(),
             ],
-            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+            &[&[AUTHORITY_SEED, &[market.authority_bump]]],
         )?;
 
         // Update obligation state
-        obligation.zbtc_deposit = obligation
-            .zbtc_deposit
-            .checked_sub(amount)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        obligation.remove_deposit(market_account.key, amount)?;
+        market.remove_deposits(amount)?;
+
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
 
-        // Save updated obligation data
-        obligation.serialize(&mut *obligation_account.data.borrow_mut())?;
+        // Save updated obligation and market data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
 
-        msg!("Withdrawn {} ZBTC", amount);
+        ZFubaoEvent::CollateralWithdrawn {
+            obligation: *obligation_account.key,
+            market: *market_account.key,
+            amount,
+        }
+        .emit()?;
+
+        msg!(
+            "Withdrawn {} collateral of market {}",
+            amount,
+            market_account.key
+        );
         Ok(())
     }
 
@@ -381,54 +658,76 @@ impl Processor {
 
         let user = next_account_info(account_info_iter)?;
         let authority_account = next_account_info(account_info_iter)?;
-        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
         let obligation_account = next_account_info(account_info_iter)?;
         let user_zusd_account = next_account_info(account_info_iter)?;
         let zusd_mint = next_account_info(account_info_iter)?;
         let token_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
 
         // Check signer
         if !user.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        if find_obligation_pda(&user.key, program_id).0 != *obligation_account.key {
-            return Err(ProgramError::InvalidAccountData);
-        }
-
         // Load obligation data
-        let mut obligation = Obligation::try_from_slice(&obligation_account.data.borrow())?;
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
 
-        // Verify obligation account
-        if obligation_account.owner != program_id {
-            return Err(ProgramError::InvalidAccountData);
+        if !obligation.can_be_operated_by(user.key, DelegatePermission::Full) {
+            return Err(ZFubaoError::Unauthorized.into());
         }
 
-        // Load lending state
-        let global_config = ZFubaoConfig::try_from_slice(&global_config_account.data.borrow())?;
+        // Proceeds go to the owner when a delegate operates the obligation
+        if *user.key != obligation.owner {
+            Self::check_token_account_owner(user_zusd_account, &obligation.owner)?;
+        }
 
-        // Verify lending state account
-        if global_config_account.owner != program_id {
-            return Err(ProgramError::InvalidAccountData);
+        // Load the market the obligation lives in
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+
+        // Markets are settled one by one after the global settlement, none lends
+        // in between
+        let global_config = Self::load_global_config(program_id, global_config_account)?;
+        if global_config.is_settled() || market.settled {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        // Debt is only ever minted as real ZUSD
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
         }
 
-        // Calculate maximum borrowable amount
-        let max_borrowable = Self::calculate_max_borrowable(&obligation, &global_config)?;
+        // Calculate maximum borrowable amount across every deposit
+        let markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            account_info_iter.as_slice(),
+        )?;
+        let position = Self::calculate_position_status(&obligation, &markets)?;
+
+        if position.is_undercollateralized() {
+            msg!("Obligation is undercollateralized, nothing can be borrowed");
+            return Err(ZFubaoError::Undercollateralized.into());
+        }
 
-        msg!("max borrowable: {}", max_borrowable);
+        msg!("max borrowable: {}", position.max_borrowable);
 
         // Check if borrow amount is within limits
-        if amount > max_borrowable {
-            return Err(ProgramError::InvalidArgument);
+        if amount > position.max_borrowable {
+            return Err(ZFubaoError::BorrowLimitExceeded.into());
         }
 
+        market.add_borrowed(amount)?;
+
         // Mint ZUSD tokens to user's account
         invoke_signed(
             &spl_token::instruction::mint_to(
                 token_program.key,
                 zusd_mint.key,
                 user_zusd_account.key,
-                &authority_account.key,
+                authority_account.key,
                 &[],
                 amount,
             )?,
@@ -438,7 +737,7 @@ impl Processor {
                 token_program.clone(),
                 authority_account.clone(),
             ],
-            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+            &[&[AUTHORITY_SEED, &[market.authority_bump]]],
         )?;
 
         // Update obligation state
@@ -447,8 +746,17 @@ impl Processor {
             .checked_add(amount)
             .ok_or(ProgramError::ArithmeticOverflow)?;
 
-        // Save updated obligation data
-        obligation.serialize(&mut *obligation_account.data.borrow_mut())?;
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+
+        // Save updated obligation and market data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::ZusdBorrowed {
+            obligation: *obligation_account.key,
+            amount,
+        }
+        .emit()?;
 
         msg!("Borrowed {} ZUSD", amount);
         Ok(())
@@ -461,56 +769,55 @@ impl Processor {
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
-        let user = next_account_info(account_info_iter)?;
-        let authority_account = next_account_info(account_info_iter)?;
-        let global_config_account = next_account_info(account_info_iter)?;
+        let payer = next_account_info(account_info_iter)?;
+        let _authority_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
         let obligation_account = next_account_info(account_info_iter)?;
-        let user_zusd_account = next_account_info(account_info_iter)?;
+        let payer_zusd_account = next_account_info(account_info_iter)?;
         let zusd_mint = next_account_info(account_info_iter)?;
         let token_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
 
         // Check signer
-        if !user.is_signer {
+        if !payer.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        if find_obligation_pda(&user.key, program_id).0 != *obligation_account.key {
-            return Err(ProgramError::InvalidAccountData);
-        }
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
 
-        // Load global config
-        let global_config = ZFubaoConfig::try_from_slice(&global_config_account.data.borrow())?;
+        // Load beneficiary obligation data, anyone can repay it
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
 
-        // Load obligation data
-        let mut obligation = Obligation::try_from_slice(&obligation_account.data.borrow())?;
+        // Load the market the obligation lives in
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
 
-        // Verify obligation account
-        if obligation_account.owner != program_id {
-            return Err(ProgramError::InvalidAccountData);
+        // Only burning real ZUSD pays down debt
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
         }
 
         // Check if repay amount is valid
         if amount > obligation.zusd_borrowed {
-            return Err(ProgramError::InvalidArgument);
+            return Err(ZFubaoError::RepayExceedsDebt.into());
         }
 
         // Burn the ZUSD tokens
-        invoke_signed(
+        invoke(
             &spl_token::instruction::burn(
                 token_program.key,
-                user_zusd_account.key,
+                payer_zusd_account.key,
                 zusd_mint.key,
-                &user.key,
+                payer.key,
                 &[],
                 amount,
             )?,
             &[
-                user_zusd_account.clone(),
+                payer_zusd_account.clone(),
                 zusd_mint.clone(),
-                user.clone(),
+                payer.clone(),
                 token_program.clone(),
             ],
-            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
         )?;
 
         // Update obligation state
@@ -518,11 +825,26 @@ impl Processor {
             .zusd_borrowed
             .checked_sub(amount)
             .ok_or(ProgramError::ArithmeticOverflow)?;
+        market.remove_borrowed(amount)?;
 
-        // Save updated obligation data
-        obligation.serialize(&mut *obligation_account.data.borrow_mut())?;
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+
+        // Save updated obligation and market data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::ZusdRepaid {
+            obligation: *obligation_account.key,
+            payer: *payer.key,
+            amount,
+        }
+        .emit()?;
 
-        msg!("Repaid {} ZUSD", amount);
+        msg!(
+            "Repaid {} ZUSD for obligation of {}",
+            amount,
+            obligation.owner
+        );
         Ok(())
     }
 
@@ -534,18 +856,33 @@ impl Processor {
         let global_config_account = next_account_info(accounts_iter)?;
         let user_zusd_account = next_account_info(accounts_iter)?;
         let user_szusd_account = next_account_info(accounts_iter)?;
-        let zusd_mint = next_account_info(accounts_iter)?;
+        let _zusd_mint = next_account_info(accounts_iter)?;
         let szusd_mint = next_account_info(accounts_iter)?;
         let staking_vault = next_account_info(accounts_iter)?;
         let token_program = next_account_info(accounts_iter)?;
-        let system_program = next_account_info(accounts_iter)?;
+        let _system_program = next_account_info(accounts_iter)?;
 
         if !user_account.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
         // Load global config
-        let global_config = ZFubaoConfig::try_from_slice(&global_config_account.data.borrow())?;
+        let global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // Staking would mint shares at a price that no longer moves
+        if global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        // szUSD is collateral, so only the real share mint and staking vault will do
+        if *szusd_mint.key != global_config.szusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        if *staking_vault.key
+            != get_associated_token_address(&global_config.authority, &global_config.zusd_mint)
+        {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
 
         invoke(
             &spl_token::instruction::transfer(
@@ -564,11 +901,13 @@ impl Processor {
             ],
         )?;
 
-        let adjusted_amount = amount
-            .checked_mul(global_config.szusd_price_ratio)
-            .ok_or(ProgramError::ArithmeticOverflow)?
-            .checked_div(10000)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        // Shares are priced in ZUSD, round down so staking never mints extra
+        let adjusted_amount = Decimal::from_u64(amount)
+            .try_div(
+                global_config.get_current_szusd_price_in_zusd(),
+                Rounding::Down,
+            )?
+            .to_u64(Rounding::Down)?;
 
         // Only the program can mint SZUSD
         invoke_signed(
@@ -576,7 +915,7 @@ impl Processor {
                 token_program.key,
                 szusd_mint.key,
                 user_szusd_account.key,
-                &authority_account.key,
+                authority_account.key,
                 &[],
                 adjusted_amount,
             )?,
@@ -592,7 +931,7 @@ impl Processor {
         msg!(
             "Successfully staked {} ZUSD and minted {} SZUSD",
             amount,
-            amount
+            adjusted_amount
         );
         Ok(())
     }
@@ -600,30 +939,30 @@ impl Processor {
     fn process_refresh_price(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
 
-        let authority_account = next_account_info(account_info_iter)?;
+        let _authority_account = next_account_info(account_info_iter)?;
         let global_config_account = next_account_info(account_info_iter)?;
+        let szusd_market_account = next_account_info(account_info_iter)?;
 
-        let mut global_config = ZFubaoConfig::try_from_slice(&global_config_account.data.borrow())?;
-
-        let current_time = Clock::get()?.unix_timestamp;
-        let time_elapsed = current_time
-            .checked_sub(global_config.start_time)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
 
-        let seconds_elapsed = time_elapsed
-            .checked_div(1000)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        // GlobalSettlement accrued the price one last time
+        if global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
 
-        let new_ratio = global_config
-            .szusd_price_ratio
-            .checked_add(seconds_elapsed as u64)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        global_config.accrue_szusd_price(Clock::get()?.unix_timestamp)?;
 
-        global_config.szusd_price_ratio = new_ratio;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
 
-        global_config.serialize(&mut *global_config_account.data.borrow_mut())?;
+        // The szUSD market values its collateral at the rate it was last synced to.
+        // Accrual only grows the rate, and every write-down syncs the market itself,
+        // so between refreshes the market stays conservative.
+        Self::sync_szusd_market(program_id, szusd_market_account, &global_config)?;
 
-        msg!("Price refreshed");
+        msg!(
+            "Price refreshed: {} ZUSD per SZUSD",
+            global_config.get_current_szusd_price_in_zusd()
+        );
         Ok(())
     }
 
@@ -640,18 +979,28 @@ impl Processor {
         let global_config_account = next_account_info(accounts_iter)?;
         let user_zusd_account = next_account_info(accounts_iter)?;
         let user_szusd_account = next_account_info(accounts_iter)?;
-        let zusd_mint = next_account_info(accounts_iter)?;
+        let _zusd_mint = next_account_info(accounts_iter)?;
         let szusd_mint = next_account_info(accounts_iter)?;
         let staking_vault = next_account_info(accounts_iter)?;
         let token_program = next_account_info(accounts_iter)?;
-        let system_program = next_account_info(accounts_iter)?;
+        let _system_program = next_account_info(accounts_iter)?;
 
         if !user_account.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
         // Load global config
-        let global_config = ZFubaoConfig::try_from_slice(&global_config_account.data.borrow())?;
+        let global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // szUSD is collateral, so only the real share mint and staking vault will do
+        if *szusd_mint.key != global_config.szusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        if *staking_vault.key
+            != get_associated_token_address(&global_config.authority, &global_config.zusd_mint)
+        {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
 
         invoke(
             &spl_token::instruction::burn(
@@ -670,17 +1019,18 @@ impl Processor {
             ],
         )?;
 
+        // Round down so unstaking never pays out more than the shares are worth
         let current_szusd_price = global_config.get_current_szusd_price_in_zusd();
-        let amount_in_zusd = amount
-            .checked_mul(current_szusd_price)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        let amount_in_zusd = Decimal::from_u64(amount)
+            .try_mul(current_szusd_price, Rounding::Down)?
+            .to_u64(Rounding::Down)?;
 
         invoke_signed(
             &spl_token::instruction::transfer(
                 token_program.key,
                 staking_vault.key,
                 user_zusd_account.key,
-                &authority_account.key,
+                authority_account.key,
                 &[],
                 amount_in_zusd,
             )?,
@@ -696,62 +1046,5013 @@ impl Processor {
         msg!(
             "Successfully unstaked {} SZUSD and returned {} ZUSD",
             amount,
-            amount
+            amount_in_zusd
         );
         Ok(())
     }
 
-    // Helper function to calculate maximum borrowable amount
-    pub fn calculate_max_borrowable(
-        obligation: &Obligation,
-        global_config: &ZFubaoConfig,
-    ) -> Result<u64, ProgramError> {
-        // Calculate collateral value in USD
-        let collateral_value = obligation
-            .zbtc_deposit
-            .checked_mul(global_config.price)
-            .ok_or(ProgramError::ArithmeticOverflow)?
-            .div(1_000); // Decimal precision adjustment
+    fn process_close_obligation(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
 
-        // Calculate maximum borrowable amount based on LTV ratio
-        let max_borrowable = collateral_value
-            .checked_mul(global_config.ltv_ratio as u64)
-            .ok_or(ProgramError::ArithmeticOverflow)?
-            .checked_div(100)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let user_zbtc_account = next_account_info(account_info_iter)?;
+        let vault_zbtc_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let trigger_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
 
-        Ok(max_borrowable - obligation.zusd_borrowed)
-    }
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
 
-    // Helper function to calculate maximum withdrawable amount
-    pub fn calculate_max_withdrawable(
-        obligation: &Obligation,
-        global_config: &ZFubaoConfig,
-    ) -> Result<u64, ProgramError> {
-        // Calculate collateral value in USD
-        let collateral_value = obligation
-            .zbtc_deposit
-            .checked_mul(global_config.price)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
 
-        // Calculate minimum required collateral value based on borrowed amount and LTV ratio
-        let min_collateral_value = obligation
-            .zusd_borrowed
-            .checked_mul(100)
-            .ok_or(ProgramError::ArithmeticOverflow)?
-            .checked_div(global_config.ltv_ratio as u64)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        // Load obligation data
+        let obligation = Self::load_obligation(program_id, obligation_account)?;
 
-        // Calculate maximum withdrawable collateral value
-        let max_withdrawable_value = collateral_value
-            .checked_sub(min_collateral_value)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        // Only the owner can close, delegates never receive the rent
+        if obligation.owner != *user.key {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
 
-        // Convert back to ZBTC
-        let max_withdrawable = max_withdrawable_value
-            .checked_div(global_config.price)
-            .ok_or(ProgramError::ArithmeticOverflow)?;
+        // Load the market holding the last of the collateral
+        let mut market = Self::load_market(program_id, market_account)?;
 
-        Ok(max_withdrawable)
+        // The authority owns every market's vault, so pay out of this market's only
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
+        // Outstanding debt has to be repaid before the obligation can go away
+        if obligation.zusd_borrowed != 0 {
+            msg!("Obligation still owes {} ZUSD", obligation.zusd_borrowed);
+            return Err(ZFubaoError::OutstandingDebt.into());
+        }
+
+        // Leftovers of a running auction go back to the owner, not the obligation
+        // (they are not in its deposits), but its bids still need the account
+        if obligation.auctions != 0 {
+            return Err(ZFubaoError::AuctionInProgress.into());
+        }
+
+        // Only one vault is passed, so at most one market can still hold collateral
+        if obligation
+            .deposits()
+            .any(|deposit| deposit.market != *market_account.key)
+        {
+            return Err(ZFubaoError::OutstandingCollateral.into());
+        }
+
+        // Return any remaining collateral to the user
+        let deposit = obligation.deposited(market_account.key);
+        if deposit > 0 {
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    vault_zbtc_account.key,
+                    user_zbtc_account.key,
+                    authority_account.key,
+                    &[],
+                    deposit,
+                )?,
+                &[
+                    vault_zbtc_account.clone(),
+                    user_zbtc_account.clone(),
+                    authority_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[AUTHORITY_SEED, &[market.authority_bump]]],
+            )?;
+
+            msg!("Withdrawn {} collateral", deposit);
+        }
+
+        market.remove_deposits(deposit)?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        // A later obligation at the same address must not inherit the trigger
+        Self::close_trigger(program_id, obligation_account.key, trigger_account, user)?;
+
+        // Move the rent lamports back to the user
+        let user_lamports = user.lamports();
+        **user.lamports.borrow_mut() = user_lamports
+            .checked_add(obligation_account.lamports())
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        **obligation_account.lamports.borrow_mut() = 0;
+
+        // Wipe the data and hand the account back to the system program so that
+        // a later InitObligation starts from a clean slate
+        obligation_account.data.borrow_mut().fill(0);
+        obligation_account.realloc(0, false)?;
+        obligation_account.assign(&system_program::id());
+
+        msg!("Obligation closed for user {}", user.key);
+        Ok(())
+    }
+
+    fn process_set_delegate(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        delegate: Pubkey,
+        permission: DelegatePermission,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let owner = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
+
+        // Load obligation data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        // Delegates cannot hand out or change permissions
+        if obligation.owner != *owner.key {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
+
+        if permission == DelegatePermission::None {
+            obligation.delegate = Pubkey::default();
+        } else {
+            obligation.delegate = delegate;
+        }
+        obligation.delegate_permission = permission;
+
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+
+        msg!(
+            "Delegate of obligation {} set to {} with {:?}",
+            obligation_account.key,
+            obligation.delegate,
+            permission
+        );
+        Ok(())
+    }
+
+    fn process_transfer_obligation(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let current_owner = next_account_info(account_info_iter)?;
+        let new_owner = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let trigger_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Both parties have to agree to the transfer
+        if !current_owner.is_signer || !new_owner.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
+
+        // Load obligation data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        if obligation.owner != *current_owner.key {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
+
+        // Auction leftovers go to the owner the auction was started against
+        if obligation.auctions != 0 {
+            return Err(ZFubaoError::AuctionInProgress.into());
+        }
+
+        // The previous owner's delegate should not keep access to the new owner's position
+        obligation.owner = *new_owner.key;
+        obligation.delegate = Pubkey::default();
+        obligation.delegate_permission = DelegatePermission::None;
+
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+
+        // Nor should the previous owner's trigger keep selling its collateral
+        Self::close_trigger(
+            program_id,
+            obligation_account.key,
+            trigger_account,
+            current_owner,
+        )?;
+
+        msg!(
+            "Obligation {} transferred from {} to {}",
+            obligation_account.key,
+            current_owner.key,
+            new_owner.key
+        );
+        Ok(())
+    }
+
+    fn process_deposit_and_borrow(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        deposit_amount: u64,
+        borrow_amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let user_zbtc_account = next_account_info(account_info_iter)?;
+        let vault_zbtc_account = next_account_info(account_info_iter)?;
+        let user_zusd_account = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Load obligation data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        if !obligation.can_be_operated_by(user.key, DelegatePermission::Full) {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
+
+        // Proceeds go to the owner when a delegate operates the obligation
+        if *user.key != obligation.owner {
+            Self::check_token_account_owner(user_zusd_account, &obligation.owner)?;
+        }
+
+        // Load the market the obligation lives in
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+
+        // Markets are settled one by one after the global settlement, none lends
+        // in between
+        let global_config = Self::load_global_config(program_id, global_config_account)?;
+        if global_config.is_settled() || market.settled {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        // Debt is only ever minted as real ZUSD
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // Collateral only counts if it actually lands in the market's vault
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
+        let markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            account_info_iter.as_slice(),
+        )?;
+
+        // Update obligation state and check the final position once
+        obligation.add_deposit(market_account.key, deposit_amount)?;
+        obligation.zusd_borrowed = obligation
+            .zusd_borrowed
+            .checked_add(borrow_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        Self::check_ltv(&obligation, &markets)?;
+        market.add_deposits(deposit_amount)?;
+        market.add_borrowed(borrow_amount)?;
+
+        // Transfer ZBTC from user to vault
+        invoke(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                user_zbtc_account.key,
+                vault_zbtc_account.key,
+                user.key,
+                &[],
+                deposit_amount,
+            )?,
+            &[
+                user_zbtc_account.clone(),
+                vault_zbtc_account.clone(),
+                user.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        // Mint ZUSD tokens to user's account
+        invoke_signed(
+            &spl_token::instruction::mint_to(
+                token_program.key,
+                zusd_mint.key,
+                user_zusd_account.key,
+                authority_account.key,
+                &[],
+                borrow_amount,
+            )?,
+            &[
+                zusd_mint.clone(),
+                user_zusd_account.clone(),
+                token_program.clone(),
+                authority_account.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[market.authority_bump]]],
+        )?;
+
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+
+        // Save updated obligation and market data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::CollateralDeposited {
+            obligation: *obligation_account.key,
+            market: *market_account.key,
+            payer: *user.key,
+            amount: deposit_amount,
+        }
+        .emit()?;
+        ZFubaoEvent::ZusdBorrowed {
+            obligation: *obligation_account.key,
+            amount: borrow_amount,
+        }
+        .emit()?;
+
+        msg!(
+            "Deposited {} collateral and borrowed {} ZUSD",
+            deposit_amount,
+            borrow_amount
+        );
+        Ok(())
+    }
+
+    fn process_repay_and_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        repay_amount: u64,
+        withdraw_amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let user_zusd_account = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let user_zbtc_account = next_account_info(account_info_iter)?;
+        let vault_zbtc_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
+
+        // Load obligation data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        if !obligation.can_be_operated_by(user.key, DelegatePermission::Full) {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
+
+        // Proceeds go to the owner when a delegate operates the obligation
+        if *user.key != obligation.owner {
+            Self::check_token_account_owner(user_zbtc_account, &obligation.owner)?;
+        }
+
+        // Load the market the obligation lives in
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+
+        // Only burning real ZUSD pays down debt
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // The authority owns every market's vault, so pay out of this market's only
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
+        // Check if repay amount is valid
+        if repay_amount > obligation.zusd_borrowed {
+            return Err(ZFubaoError::RepayExceedsDebt.into());
+        }
+
+        let markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            account_info_iter.as_slice(),
+        )?;
+
+        // Update obligation state and check the final position once
+        obligation.zusd_borrowed = obligation
+            .zusd_borrowed
+            .checked_sub(repay_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        obligation.remove_deposit(market_account.key, withdraw_amount)?;
+
+        Self::check_ltv(&obligation, &markets)?;
+        market.remove_borrowed(repay_amount)?;
+        market.remove_deposits(withdraw_amount)?;
+
+        // Burn the ZUSD tokens
+        invoke(
+            &spl_token::instruction::burn(
+                token_program.key,
+                user_zusd_account.key,
+                zusd_mint.key,
+                user.key,
+                &[],
+                repay_amount,
+            )?,
+            &[
+                user_zusd_account.clone(),
+                zusd_mint.clone(),
+                user.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        // Transfer ZBTC from vault to user
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                vault_zbtc_account.key,
+                user_zbtc_account.key,
+                authority_account.key,
+                &[],
+                withdraw_amount,
+            )?,
+            &[
+                vault_zbtc_account.clone(),
+                user_zbtc_account.clone(),
+                authority_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[market.authority_bump]]],
+        )?;
+
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+
+        // Save updated obligation and market data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::ZusdRepaid {
+            obligation: *obligation_account.key,
+            payer: *user.key,
+            amount: repay_amount,
+        }
+        .emit()?;
+        ZFubaoEvent::CollateralWithdrawn {
+            obligation: *obligation_account.key,
+            market: *market_account.key,
+            amount: withdraw_amount,
+        }
+        .emit()?;
+
+        msg!(
+            "Repaid {} ZUSD and withdrawn {} collateral",
+            repay_amount,
+            withdraw_amount
+        );
+        Ok(())
+    }
+
+    fn process_get_obligation_health(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
+
+        // Load obligation data
+        let obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        // Any market can be queried, max_withdrawable and the liquidation price
+        // refer to its collateral
+        let market = Self::load_market(program_id, market_account)?;
+
+        let markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            account_info_iter.as_slice(),
+        )?;
+        let health = Self::calculate_obligation_health(&obligation, &markets)?;
+        set_return_data(&borsh::to_vec(&health)?);
+
+        msg!(
+            "Health of obligation {}: {:?}",
+            obligation_account.key,
+            health
+        );
+        Ok(())
+    }
+
+    fn process_init_market(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        ltv_ratio: u16,
+        price: u64,
+        deposit_cap: u64,
+        borrow_cap: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let admin = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let collateral_mint = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        let mut global_config =
+            Self::load_global_config_as_admin(program_id, global_config_account, admin)?;
+
+        if global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        // ZUSD cannot back its own debt
+        if *collateral_mint.key == global_config.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // szUSD is valued at the staking exchange rate rather than an admin price
+        let (price_source, price) = if *collateral_mint.key == global_config.szusd_mint {
+            (
+                PriceSource::SzusdExchangeRate,
+                global_config.szusd_price_ratio,
+            )
+        } else {
+            (PriceSource::Admin, price)
+        };
+
+        // LTV is in basis points, capped lower for collateral that can be looped
+        if ltv_ratio > price_source.max_ltv_ratio() {
+            return Err(ZFubaoError::InvalidLtvRatio.into());
+        }
+
+        let (market_pda, market_bump) = find_market_pda(collateral_mint.key, program_id);
+        if *market_account.key != market_pda {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if !market_account.data_is_empty() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        // Value math is normalized with the decimals of the actual mint
+        let collateral_decimals = Self::load_mint(collateral_mint)?.decimals;
+
+        msg!("Create market account");
+
+        Self::create_pda_account(
+            admin,
+            market_account,
+            system_program,
+            program_id,
+            Market::LEN,
+            &[MARKET_SEED, collateral_mint.key.as_ref(), &[market_bump]],
+        )?;
+
+        let market = Market {
+            authority: global_config.authority,
+
+            collateral_mint: *collateral_mint.key,
+            zusd_mint: global_config.zusd_mint,
+            collateral_decimals,
+            zusd_decimals: global_config.zusd_decimals,
+
+            authority_bump: global_config.authority_bump,
+            market_bump,
+
+            ltv_ratio,
+            price_source,
+            price,
+            deposit_cap,
+            borrow_cap,
+            total_deposits: 0,
+            total_borrowed: 0,
+
+            settled: false,
+            settled_collateral: 0,
+
+            flash_loan_due: 0,
+
+            redemption_price: price,
+            redemption_buckets: [0; REDEMPTION_BUCKETS],
+        };
+
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        // Settlement redemptions wait for every market
+        global_config.market_count = global_config
+            .market_count
+            .checked_add(1)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        msg!(
+            "Market {} opened for collateral {}",
+            market_account.key,
+            collateral_mint.key
+        );
+        Ok(())
+    }
+
+    fn process_update_market(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        ltv_ratio: u16,
+        price: u64,
+        deposit_cap: u64,
+        borrow_cap: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let admin = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+
+        Self::load_global_config_as_admin(program_id, global_config_account, admin)?;
+
+        let mut market = Self::load_market(program_id, market_account)?;
+
+        // Settlement pays out at the frozen price
+        if market.settled {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        // LTV is in basis points, capped lower for collateral that can be looped
+        if ltv_ratio > market.price_source.max_ltv_ratio() {
+            return Err(ZFubaoError::InvalidLtvRatio.into());
+        }
+
+        market.ltv_ratio = ltv_ratio;
+        // The szUSD rate only moves through RefreshPrice
+        if market.price_source == PriceSource::Admin {
+            market.price = price;
+        }
+        market.deposit_cap = deposit_cap;
+        market.borrow_cap = borrow_cap;
+
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        msg!(
+            "Market {} updated: ltv {} bps, price {}, deposit cap {}, borrow cap {}",
+            market_account.key,
+            ltv_ratio,
+            market.price,
+            deposit_cap,
+            borrow_cap
+        );
+        Ok(())
+    }
+
+    fn process_redeem(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let redeemer = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let redeemer_zusd_account = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let redeemer_zbtc_account = next_account_info(account_info_iter)?;
+        let vault_zbtc_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let insurance_fund = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !redeemer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Settled ZUSD redeems pro-rata through RedeemSettlement instead
+        let global_config = Self::load_global_config(program_id, global_config_account)?;
+        if global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        // Load the market paying out the collateral
+        let mut market = Self::load_market(program_id, market_account)?;
+
+        // Only burning real ZUSD redeems collateral
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        Self::check_insurance_fund(program_id, zusd_mint.key, insurance_fund)?;
+
+        // The authority owns every market's vault, so pay out of this market's only
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
+        let mut zusd_spent: u64 = 0;
+        let mut zusd_redeemed: u64 = 0;
+        let mut insurance_fees: u64 = 0;
+        let mut collateral_redeemed: u64 = 0;
+
+        for obligation_account in account_info_iter {
+            if zusd_spent == amount {
+                break;
+            }
+
+            let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+            // The market tracks the debt it lent, so it only redeems its own obligations
+            if obligation.market != *market_account.key {
+                return Err(ZFubaoError::MarketMismatch.into());
+            }
+
+            // Without debt or collateral in the market there is nothing to redeem
+            if obligation.redemption_bucket == NO_REDEMPTION_BUCKET {
+                continue;
+            }
+
+            // Redemptions hit the riskiest obligations first, so none may be left
+            // in a lower bucket
+            let riskiest_bucket = market
+                .redemption_buckets
+                .iter()
+                .position(|count| *count != 0);
+            if riskiest_bucket != Some(obligation.redemption_bucket as usize) {
+                return Err(ZFubaoError::ObligationsNotSorted.into());
+            }
+
+            let redemption = Self::calculate_redemption(
+                &obligation,
+                market_account.key,
+                &market,
+                amount - zusd_spent,
+            )?;
+            // What is left of the ZUSD or of the collateral is too little to redeem
+            if redemption.zusd_amount == 0 {
+                break;
+            }
+
+            // Update obligation state
+            obligation.zusd_borrowed = obligation
+                .zusd_borrowed
+                .checked_sub(redemption.zusd_amount)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+            obligation.remove_deposit(market_account.key, redemption.collateral_amount)?;
+            market.remove_borrowed(redemption.zusd_amount)?;
+            market.remove_deposits(redemption.collateral_amount)?;
+            Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+
+            obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+
+            zusd_redeemed = zusd_redeemed
+                .checked_add(redemption.zusd_amount)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+            insurance_fees = insurance_fees
+                .checked_add(redemption.insurance_fee)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+            zusd_spent = zusd_redeemed
+                .checked_add(insurance_fees)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+            collateral_redeemed = collateral_redeemed
+                .checked_add(redemption.collateral_amount)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+
+            ZFubaoEvent::CollateralRedeemed {
+                obligation: *obligation_account.key,
+                market: *market_account.key,
+                redeemer: *redeemer.key,
+                zusd_amount: redemption.zusd_amount,
+                collateral_amount: redemption.collateral_amount,
+                fee: redemption.fee,
+                insurance_fee: redemption.insurance_fee,
+            }
+            .emit()?;
+        }
+
+        // Burn the ZUSD that found debt to cancel, the rest stays with the redeemer
+        invoke(
+            &spl_token::instruction::burn(
+                token_program.key,
+                redeemer_zusd_account.key,
+                zusd_mint.key,
+                redeemer.key,
+                &[],
+                zusd_redeemed,
+            )?,
+            &[
+                redeemer_zusd_account.clone(),
+                zusd_mint.clone(),
+                redeemer.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        // The insurance fund's share of the fee, bought by the redeemer
+        if insurance_fees > 0 {
+            invoke(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    redeemer_zusd_account.key,
+                    insurance_fund.key,
+                    redeemer.key,
+                    &[],
+                    insurance_fees,
+                )?,
+                &[
+                    redeemer_zusd_account.clone(),
+                    insurance_fund.clone(),
+                    redeemer.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+        }
+
+        // Transfer the redeemed collateral from vault to redeemer
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                vault_zbtc_account.key,
+                redeemer_zbtc_account.key,
+                authority_account.key,
+                &[],
+                collateral_redeemed,
+            )?,
+            &[
+                vault_zbtc_account.clone(),
+                redeemer_zbtc_account.clone(),
+                authority_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[market.authority_bump]]],
+        )?;
+
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        msg!(
+            "Redeemed {} ZUSD for {} collateral of market {}, {} ZUSD to the insurance fund",
+            zusd_redeemed,
+            collateral_redeemed,
+            market_account.key,
+            insurance_fees
+        );
+        Ok(())
+    }
+
+    fn process_liquidate(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let liquidator = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let collateral_market_account = next_account_info(account_info_iter)?;
+        let staking_vault = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !liquidator.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // Load obligation data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        // Load the market the obligation lives in
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+
+        // Only burning real ZUSD out of the staking vault cancels debt
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        if *staking_vault.key
+            != get_associated_token_address(&global_config.authority, &global_config.zusd_mint)
+        {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
+
+        // Only positions past the LTV limit can be liquidated
+        let deposit_market_accounts = account_info_iter.as_slice();
+        let markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            deposit_market_accounts,
+        )?;
+        let position = Self::calculate_position_status(&obligation, &markets)?;
+
+        if !position.is_undercollateralized() {
+            return Err(ZFubaoError::ObligationHealthy.into());
+        }
+
+        // The seized collateral has to be one of the obligation's deposits
+        if obligation.deposited(collateral_market_account.key) == 0 {
+            return Err(ZFubaoError::DepositMarketMismatch.into());
+        }
+        let (_, collateral_market) = markets
+            .iter()
+            .find(|(key, _)| key == collateral_market_account.key)
+            .ok_or(ZFubaoError::DepositMarketMismatch)?;
+
+        // Gains need stakers to go to, and debt needs ZUSD to cancel it
+        let vault_balance = spl_token::state::Account::unpack(&staking_vault.data.borrow())?.amount;
+        let available_zusd = global_config.stability_pool_capacity(vault_balance);
+        if available_zusd == 0 || global_config.total_staked_shares == 0 {
+            return Err(ZFubaoError::StabilityPoolEmpty.into());
+        }
+
+        let liquidation = Self::calculate_liquidation(
+            &obligation,
+            collateral_market_account.key,
+            collateral_market,
+            &market,
+            available_zusd,
+        )?;
+
+        // Update obligation state
+        obligation.zusd_borrowed = obligation
+            .zusd_borrowed
+            .checked_sub(liquidation.zusd_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        obligation.remove_deposit(collateral_market_account.key, liquidation.collateral_amount)?;
+        market.remove_borrowed(liquidation.zusd_amount)?;
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        // The collateral market may be the market of the obligation, so load it
+        // after that is saved. The seized collateral stays in its vault for the
+        // stakers to claim.
+        let mut collateral_market = Self::load_market(program_id, collateral_market_account)?;
+        collateral_market.remove_deposits(liquidation.collateral_amount)?;
+        collateral_market.serialize(&mut &mut collateral_market_account.data.borrow_mut()[..])?;
+
+        // Stakers give up the burned ZUSD for the collateral
+        global_config.write_down_szusd_price(vault_balance, liquidation.zusd_amount)?;
+        global_config
+            .add_pool_gains(collateral_market_account.key, liquidation.collateral_amount)?;
+
+        invoke_signed(
+            &spl_token::instruction::burn(
+                token_program.key,
+                staking_vault.key,
+                zusd_mint.key,
+                authority_account.key,
+                &[],
+                liquidation.zusd_amount,
+            )?,
+            &[
+                staking_vault.clone(),
+                zusd_mint.clone(),
+                authority_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+        )?;
+
+        // Save updated obligation and global config data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        // The SZUSD market follows the deposit markets, keep its price in step
+        let szusd_market_account = deposit_market_accounts
+            .get(markets.len() - 1)
+            .ok_or(ProgramError::NotEnoughAccountKeys)?;
+        Self::sync_szusd_market(program_id, szusd_market_account, &global_config)?;
+
+        ZFubaoEvent::ObligationLiquidated {
+            obligation: *obligation_account.key,
+            market: *collateral_market_account.key,
+            liquidator: *liquidator.key,
+            zusd_amount: liquidation.zusd_amount,
+            collateral_amount: liquidation.collateral_amount,
+        }
+        .emit()?;
+
+        msg!(
+            "Liquidated {} ZUSD of debt for {} collateral of market {}",
+            liquidation.zusd_amount,
+            liquidation.collateral_amount,
+            collateral_market_account.key
+        );
+        Ok(())
+    }
+
+    fn process_deposit_szusd(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let _authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let user_szusd_account = next_account_info(account_info_iter)?;
+        let szusd_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let staker_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // Liquidations have stopped, there is nothing left to earn
+        if global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        if *szusd_mint.key != global_config.szusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        let mut staker = match Self::load_staker(program_id, user.key, staker_account)? {
+            Some(staker) => staker,
+            None => Self::create_staker(program_id, user, staker_account, system_program)?,
+        };
+
+        // Shares in the pool are burned SZUSD, so they cannot move without the program
+        invoke(
+            &spl_token::instruction::burn(
+                token_program.key,
+                user_szusd_account.key,
+                szusd_mint.key,
+                user.key,
+                &[],
+                amount,
+            )?,
+            &[
+                user_szusd_account.clone(),
+                szusd_mint.clone(),
+                user.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        // Record the new shares, gains so far belong to the old ones
+        staker.settle_gains(&global_config)?;
+        staker.shares = staker
+            .shares
+            .checked_add(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        global_config.total_staked_shares = global_config
+            .total_staked_shares
+            .checked_add(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        staker.serialize(&mut &mut staker_account.data.borrow_mut()[..])?;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        msg!("Deposited {} SZUSD into the stability pool", amount);
+        Ok(())
+    }
+
+    fn process_withdraw_szusd(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let user_szusd_account = next_account_info(account_info_iter)?;
+        let szusd_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let _system_program = next_account_info(account_info_iter)?;
+        let staker_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        if *szusd_mint.key != global_config.szusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // The staker account is a PDA of its owner, so only the owner gets here
+        let mut staker = Self::load_staker(program_id, user.key, staker_account)?
+            .ok_or(ZFubaoError::UnrecordedShares)?;
+
+        // Gains earned so far stay claimable
+        staker.settle_gains(&global_config)?;
+        staker.shares = staker
+            .shares
+            .checked_sub(amount)
+            .ok_or(ZFubaoError::UnrecordedShares)?;
+        global_config.total_staked_shares = global_config
+            .total_staked_shares
+            .checked_sub(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        invoke_signed(
+            &spl_token::instruction::mint_to(
+                token_program.key,
+                szusd_mint.key,
+                user_szusd_account.key,
+                authority_account.key,
+                &[],
+                amount,
+            )?,
+            &[
+                szusd_mint.clone(),
+                user_szusd_account.clone(),
+                token_program.clone(),
+                authority_account.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+        )?;
+
+        staker.serialize(&mut &mut staker_account.data.borrow_mut()[..])?;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        msg!("Withdrew {} SZUSD from the stability pool", amount);
+        Ok(())
+    }
+
+    fn process_claim_collateral_gains(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let staker_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let user_zbtc_account = next_account_info(account_info_iter)?;
+        let vault_zbtc_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // The staker account is a PDA of its owner, so only the owner gets here
+        let mut staker = Self::load_staker(program_id, user.key, staker_account)?
+            .ok_or(ProgramError::UninitializedAccount)?;
+
+        // The authority owns every market's vault, so pay out of this market's only
+        let market = Self::load_market(program_id, market_account)?;
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
+        staker.settle_gains(&global_config)?;
+        let amount = global_config
+            .stability_pool
+            .iter()
+            .position(|pool_market| pool_market.market == *market_account.key)
+            .map_or(0, |slot| std::mem::take(&mut staker.gains[slot].pending));
+
+        if amount > 0 {
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    vault_zbtc_account.key,
+                    user_zbtc_account.key,
+                    authority_account.key,
+                    &[],
+                    amount,
+                )?,
+                &[
+                    vault_zbtc_account.clone(),
+                    user_zbtc_account.clone(),
+                    authority_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[AUTHORITY_SEED, &[market.authority_bump]]],
+            )?;
+        }
+
+        staker.serialize(&mut &mut staker_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::CollateralGainsClaimed {
+            staker: *user.key,
+            market: *market_account.key,
+            amount,
+        }
+        .emit()?;
+
+        msg!(
+            "Claimed {} collateral of market {}",
+            amount,
+            market_account.key
+        );
+        Ok(())
+    }
+
+    fn process_start_auction(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let keeper = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let collateral_market_account = next_account_info(account_info_iter)?;
+        let auction_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !keeper.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
+
+        // Load obligation data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        // Load the market the obligation lives in
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+
+        // Only positions past the LTV limit can be auctioned
+        let markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            account_info_iter.as_slice(),
+        )?;
+        let position = Self::calculate_position_status(&obligation, &markets)?;
+
+        if !position.is_undercollateralized() {
+            return Err(ZFubaoError::ObligationHealthy.into());
+        }
+
+        let (auction_pda, bump) = find_auction_pda(
+            obligation_account.key,
+            collateral_market_account.key,
+            program_id,
+        );
+        if auction_pda != *auction_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if auction_account.owner == program_id {
+            return Err(ZFubaoError::AuctionInProgress.into());
+        }
+
+        // The auctioned collateral has to be one of the obligation's deposits
+        let collateral_amount = obligation.deposited(collateral_market_account.key);
+        if collateral_amount == 0 {
+            return Err(ZFubaoError::DepositMarketMismatch.into());
+        }
+        let (_, collateral_market) = markets
+            .iter()
+            .find(|(key, _)| key == collateral_market_account.key)
+            .ok_or(ZFubaoError::DepositMarketMismatch)?;
+
+        Self::create_pda_account(
+            keeper,
+            auction_account,
+            system_program,
+            program_id,
+            Auction::LEN,
+            &[
+                AUCTION_SEED,
+                obligation_account.key.as_ref(),
+                collateral_market_account.key.as_ref(),
+                &[bump],
+            ],
+        )?;
+
+        let oracle_price = collateral_market.collateral_price();
+        let auction = Auction {
+            obligation: *obligation_account.key,
+            market: *collateral_market_account.key,
+            owner: obligation.owner,
+            bump,
+            start_time: Clock::get()?.unix_timestamp,
+            start_price: oracle_price
+                .try_mul(Decimal::from_bps(AUCTION_START_PRICE_BPS), Rounding::Up)?
+                .to_scaled_val()?,
+            floor_price: oracle_price
+                .try_mul(Decimal::from_bps(AUCTION_FLOOR_PRICE_BPS), Rounding::Up)?
+                .to_scaled_val()?,
+            collateral_amount,
+        };
+
+        // The collateral stays in the market's vault, and in its total deposits,
+        // until it is sold or returned
+        obligation.remove_deposit(collateral_market_account.key, collateral_amount)?;
+        obligation.auctions = obligation
+            .auctions
+            .checked_add(1)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+        auction.serialize(&mut &mut auction_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::AuctionStarted {
+            auction: *auction_account.key,
+            obligation: *obligation_account.key,
+            market: *collateral_market_account.key,
+            collateral_amount,
+            start_price: auction.start_price,
+        }
+        .emit()?;
+
+        msg!(
+            "Auctioning {} collateral of market {}",
+            collateral_amount,
+            collateral_market_account.key
+        );
+        Ok(())
+    }
+
+    fn process_bid_auction(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let bidder = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let collateral_market_account = next_account_info(account_info_iter)?;
+        let auction_account = next_account_info(account_info_iter)?;
+        let bidder_zusd_account = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let bidder_collateral_account = next_account_info(account_info_iter)?;
+        let owner_collateral_account = next_account_info(account_info_iter)?;
+        let vault_collateral_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let insurance_fund = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !bidder.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
+
+        let mut auction = Self::load_auction(program_id, auction_account)?;
+        if auction.obligation != *obligation_account.key
+            || auction.market != *collateral_market_account.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let collateral_market = Self::load_market(program_id, collateral_market_account)?;
+        Self::check_market_vault(&collateral_market, vault_collateral_account)?;
+        if *zusd_mint.key != collateral_market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // Penalties go to the insurance fund
+        Self::check_insurance_fund(program_id, zusd_mint.key, insurance_fund)?;
+
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+
+        let debt = obligation.zusd_borrowed;
+        let fill = Self::calculate_auction_fill(
+            &auction,
+            &collateral_market,
+            debt,
+            amount,
+            Clock::get()?.unix_timestamp,
+        )?;
+
+        // The bid that covers the debt or takes the last collateral ends the auction
+        let repaid = fill
+            .zusd_amount
+            .checked_sub(fill.penalty)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        let remaining_debt = debt
+            .checked_sub(repaid)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        auction.collateral_amount = auction
+            .collateral_amount
+            .checked_sub(fill.collateral_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        let ended = remaining_debt == 0 || auction.collateral_amount == 0;
+
+        obligation.zusd_borrowed = remaining_debt;
+        if ended {
+            obligation.auctions = obligation
+                .auctions
+                .checked_sub(1)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+        }
+        market.remove_borrowed(repaid)?;
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+
+        if repaid > 0 {
+            invoke(
+                &spl_token::instruction::burn(
+                    token_program.key,
+                    bidder_zusd_account.key,
+                    zusd_mint.key,
+                    bidder.key,
+                    &[],
+                    repaid,
+                )?,
+                &[
+                    bidder_zusd_account.clone(),
+                    zusd_mint.clone(),
+                    bidder.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+        }
+
+        if fill.penalty > 0 {
+            invoke(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    bidder_zusd_account.key,
+                    insurance_fund.key,
+                    bidder.key,
+                    &[],
+                    fill.penalty,
+                )?,
+                &[
+                    token_program.clone(),
+                    bidder_zusd_account.clone(),
+                    insurance_fund.clone(),
+                    bidder.clone(),
+                ],
+            )?;
+        }
+
+        let signer_seeds: &[&[u8]] = &[AUTHORITY_SEED, &[collateral_market.authority_bump]];
+        if fill.collateral_amount > 0 {
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    vault_collateral_account.key,
+                    bidder_collateral_account.key,
+                    authority_account.key,
+                    &[],
+                    fill.collateral_amount,
+                )?,
+                &[
+                    vault_collateral_account.clone(),
+                    bidder_collateral_account.clone(),
+                    authority_account.clone(),
+                    token_program.clone(),
+                ],
+                &[signer_seeds],
+            )?;
+        }
+
+        ZFubaoEvent::AuctionBid {
+            auction: *auction_account.key,
+            bidder: *bidder.key,
+            zusd_amount: fill.zusd_amount,
+            collateral_amount: fill.collateral_amount,
+            penalty: fill.penalty,
+        }
+        .emit()?;
+
+        // The collateral market may be the market of the obligation, so load it
+        // after that is saved
+        let mut collateral_market = Self::load_market(program_id, collateral_market_account)?;
+        collateral_market.remove_deposits(fill.collateral_amount)?;
+
+        if !ended {
+            collateral_market
+                .serialize(&mut &mut collateral_market_account.data.borrow_mut()[..])?;
+            auction.serialize(&mut &mut auction_account.data.borrow_mut()[..])?;
+
+            msg!(
+                "Bought {} collateral for {} ZUSD",
+                fill.collateral_amount,
+                fill.zusd_amount
+            );
+            return Ok(());
+        }
+
+        // Return what is left, a sold-out auction may leave debt for WriteOffBadDebt
+        let returned_amount = auction.collateral_amount;
+        if returned_amount > 0 {
+            Self::check_token_account_owner(owner_collateral_account, &auction.owner)?;
+
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    vault_collateral_account.key,
+                    owner_collateral_account.key,
+                    authority_account.key,
+                    &[],
+                    returned_amount,
+                )?,
+                &[
+                    vault_collateral_account.clone(),
+                    owner_collateral_account.clone(),
+                    authority_account.clone(),
+                    token_program.clone(),
+                ],
+                &[signer_seeds],
+            )?;
+        }
+        collateral_market.remove_deposits(returned_amount)?;
+        collateral_market.serialize(&mut &mut collateral_market_account.data.borrow_mut()[..])?;
+
+        // Move the rent lamports to the bidder and close the auction
+        let bidder_lamports = bidder.lamports();
+        **bidder.lamports.borrow_mut() = bidder_lamports
+            .checked_add(auction_account.lamports())
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        **auction_account.lamports.borrow_mut() = 0;
+
+        auction_account.data.borrow_mut().fill(0);
+        auction_account.realloc(0, false)?;
+        auction_account.assign(&system_program::id());
+
+        ZFubaoEvent::AuctionEnded {
+            auction: *auction_account.key,
+            obligation: *obligation_account.key,
+            returned_amount,
+            remaining_debt,
+        }
+        .emit()?;
+
+        msg!(
+            "Auction ended, {} collateral returned and {} ZUSD of debt left",
+            returned_amount,
+            remaining_debt
+        );
+        Ok(())
+    }
+
+    fn process_write_off_bad_debt(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let caller = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let insurance_account = next_account_info(account_info_iter)?;
+        let insurance_fund = next_account_info(account_info_iter)?;
+        let staking_vault = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let szusd_market_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !caller.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // Load obligation data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        // Load the market the obligation lives in
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+
+        // Only ZUSD burned out of the insurance fund and the staking vault writes off debt
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        let (insurance_pda, insurance_bump) = find_insurance_pda(program_id);
+        if *insurance_account.key != insurance_pda {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if *insurance_fund.key != get_associated_token_address(&insurance_pda, zusd_mint.key) {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
+        if *staking_vault.key
+            != get_associated_token_address(&global_config.authority, &global_config.zusd_mint)
+        {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
+
+        // Bad debt is what is left once every deposit and auction is gone
+        if obligation.deposits().next().is_some() {
+            return Err(ZFubaoError::OutstandingCollateral.into());
+        }
+        if obligation.auctions != 0 {
+            return Err(ZFubaoError::AuctionInProgress.into());
+        }
+        if obligation.zusd_borrowed == 0 {
+            return Err(ZFubaoError::NoBadDebt.into());
+        }
+
+        // The insurance fund pays first, stakers cover the rest through the
+        // SZUSD exchange rate
+        let insurance_balance =
+            spl_token::state::Account::unpack(&insurance_fund.data.borrow())?.amount;
+        let insurance_amount = obligation.zusd_borrowed.min(insurance_balance);
+
+        let vault_balance = spl_token::state::Account::unpack(&staking_vault.data.borrow())?.amount;
+        let staker_amount = obligation
+            .zusd_borrowed
+            .checked_sub(insurance_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?
+            .min(global_config.stability_pool_capacity(vault_balance));
+
+        let written_off = insurance_amount
+            .checked_add(staker_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        if written_off == 0 {
+            return Err(ZFubaoError::StabilityPoolEmpty.into());
+        }
+
+        obligation.zusd_borrowed = obligation
+            .zusd_borrowed
+            .checked_sub(written_off)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        market.remove_borrowed(written_off)?;
+        global_config.write_down_szusd_price(vault_balance, staker_amount)?;
+
+        if insurance_amount > 0 {
+            invoke_signed(
+                &spl_token::instruction::burn(
+                    token_program.key,
+                    insurance_fund.key,
+                    zusd_mint.key,
+                    insurance_account.key,
+                    &[],
+                    insurance_amount,
+                )?,
+                &[
+                    insurance_fund.clone(),
+                    zusd_mint.clone(),
+                    insurance_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[INSURANCE_SEED, &[insurance_bump]]],
+            )?;
+        }
+
+        if staker_amount > 0 {
+            invoke_signed(
+                &spl_token::instruction::burn(
+                    token_program.key,
+                    staking_vault.key,
+                    zusd_mint.key,
+                    authority_account.key,
+                    &[],
+                    staker_amount,
+                )?,
+                &[
+                    staking_vault.clone(),
+                    zusd_mint.clone(),
+                    authority_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+            )?;
+        }
+
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+
+        // Save updated obligation, market and global config data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        Self::sync_szusd_market(program_id, szusd_market_account, &global_config)?;
+
+        ZFubaoEvent::BadDebtWrittenOff {
+            obligation: *obligation_account.key,
+            insurance_amount,
+            staker_amount,
+            remaining_debt: obligation.zusd_borrowed,
+        }
+        .emit()?;
+
+        msg!(
+            "Wrote off {} ZUSD from the insurance fund and {} ZUSD from stakers",
+            insurance_amount,
+            staker_amount
+        );
+        Ok(())
+    }
+
+    fn process_global_settlement(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let admin = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        let mut global_config =
+            Self::load_global_config_as_admin(program_id, global_config_account, admin)?;
+
+        // The first call starts the settlement, later ones settle markets left out
+        if !global_config.is_settled() {
+            let now = Clock::get()?.unix_timestamp;
+            global_config.accrue_szusd_price(now)?;
+            global_config.settled_at = now;
+
+            msg!("Global settlement started at {}", now);
+        }
+
+        let mut debt_free_markets = Vec::new();
+        for market_account in account_info_iter {
+            let mut market = Self::load_market(program_id, market_account)?;
+            if market.total_borrowed == 0 {
+                debt_free_markets.push(*market_account.key);
+            }
+            if market.settled {
+                continue;
+            }
+
+            // The SZUSD market freezes at the rate accrued above
+            if market.price_source == PriceSource::SzusdExchangeRate {
+                market.price = global_config.szusd_price_ratio;
+            }
+            market.settled = true;
+            market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+            ZFubaoEvent::MarketSettled {
+                market: *market_account.key,
+                price: market.price,
+            }
+            .emit()?;
+
+            msg!(
+                "Market {} settled at price {}",
+                market_account.key,
+                market.price
+            );
+        }
+
+        // Redemptions pay the same rate to every holder, so it can only be fixed
+        // once all debt is settled. Passing every market proves that, none is
+        // left out as InitMarket is refused from the first call on.
+        debt_free_markets.sort_unstable();
+        debt_free_markets.dedup();
+        if !global_config.settlement_final
+            && debt_free_markets.len() == global_config.market_count as usize
+        {
+            global_config.settlement_final = true;
+
+            msg!(
+                "Settlement final: {} ZUSD against ${}",
+                global_config.settlement_debt,
+                Decimal::from_scaled_val(global_config.settlement_value)
+            );
+        }
+
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    fn process_settle_obligation(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let caller = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let deposit_market_accounts = account_info_iter.as_slice();
+
+        // Check signer
+        if !caller.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+        if !global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolNotSettled.into());
+        }
+
+        // Load obligation data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        // Auctioned collateral is out of reach, the auction has to end first
+        if obligation.auctions != 0 {
+            return Err(ZFubaoError::AuctionInProgress.into());
+        }
+
+        // Load the market the obligation lives in and the markets of its deposits
+        let market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+        let mut markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            deposit_market_accounts,
+        )?;
+
+        // Collateral is only seized at frozen prices
+        if markets.iter().any(|(_, market)| !market.settled) {
+            return Err(ZFubaoError::ProtocolNotSettled.into());
+        }
+
+        let debt = obligation.zusd_borrowed;
+        let mut remaining_value = market.zusd_value(debt)?;
+        let mut seized_value = Decimal::zero();
+
+        for (market_key, deposit_market) in markets.iter_mut() {
+            let deposited = obligation.deposited(market_key);
+            // Worthless collateral backs nothing and stays with the owner
+            if deposited == 0
+                || remaining_value.is_zero()
+                || deposit_market.collateral_price().is_zero()
+            {
+                continue;
+            }
+
+            // Seized collateral rounds up, in the ZUSD holders' favour
+            let seized = deposit_market
+                .collateral_amount(remaining_value, Rounding::Up)?
+                .min(deposited);
+            let value = deposit_market
+                .collateral_value(seized)?
+                .min(remaining_value);
+
+            obligation.remove_deposit(market_key, seized)?;
+            deposit_market.settled_collateral = deposit_market
+                .settled_collateral
+                .checked_add(seized)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+            remaining_value = remaining_value.try_sub(value)?;
+            seized_value = seized_value.try_add(value)?;
+        }
+
+        // The whole debt is settled, a shortfall lowers the settlement rate
+        obligation.zusd_borrowed = 0;
+        markets[0].1.remove_borrowed(debt)?;
+        let (market_key, obligation_market) = &mut markets[0];
+        Self::update_redemption_bucket(&mut obligation, market_key, obligation_market)?;
+        global_config.settlement_debt = global_config
+            .settlement_debt
+            .checked_add(debt)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        global_config.settlement_value = Decimal::from_scaled_val(global_config.settlement_value)
+            .try_add(seized_value)?
+            .to_scaled_val()?;
+
+        // Save updated obligation, markets and global config data. The markets of
+        // the other deposits were loaded in the order of their accounts.
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        markets[0]
+            .1
+            .serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+        for ((_, deposit_market), deposit_market_account) in
+            markets[1..].iter().zip(deposit_market_accounts)
+        {
+            deposit_market.serialize(&mut &mut deposit_market_account.data.borrow_mut()[..])?;
+        }
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::ObligationSettled {
+            obligation: *obligation_account.key,
+            zusd_amount: debt,
+            collateral_value: seized_value.to_scaled_val()?,
+        }
+        .emit()?;
+
+        msg!(
+            "Settled {} ZUSD of debt against ${} of collateral",
+            debt,
+            seized_value
+        );
+        Ok(())
+    }
+
+    fn process_redeem_settlement(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let holder = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let holder_zusd_account = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let holder_zbtc_account = next_account_info(account_info_iter)?;
+        let vault_zbtc_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !holder.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+        if !global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolNotSettled.into());
+        }
+        if !global_config.settlement_final {
+            return Err(ZFubaoError::SettlementNotFinal.into());
+        }
+
+        // Load the market paying out the collateral
+        let mut market = Self::load_market(program_id, market_account)?;
+
+        // Only burning real ZUSD redeems settled collateral
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // The authority owns every market's vault, so pay out of this market's only
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
+        let redemption = Self::calculate_settlement_redemption(&global_config, &market, amount)?;
+        if redemption.collateral_amount == 0 {
+            return Err(ZFubaoError::SettlementPoolEmpty.into());
+        }
+
+        global_config.settlement_debt = global_config
+            .settlement_debt
+            .checked_sub(redemption.zusd_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        global_config.settlement_value = Decimal::from_scaled_val(global_config.settlement_value)
+            .try_sub(redemption.value)?
+            .to_scaled_val()?;
+        market.settled_collateral = market
+            .settled_collateral
+            .checked_sub(redemption.collateral_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        market.remove_deposits(redemption.collateral_amount)?;
+
+        // Burn the redeemed ZUSD
+        invoke(
+            &spl_token::instruction::burn(
+                token_program.key,
+                holder_zusd_account.key,
+                zusd_mint.key,
+                holder.key,
+                &[],
+                redemption.zusd_amount,
+            )?,
+            &[
+                holder_zusd_account.clone(),
+                zusd_mint.clone(),
+                holder.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        // Transfer the settled collateral from vault to holder
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                vault_zbtc_account.key,
+                holder_zbtc_account.key,
+                authority_account.key,
+                &[],
+                redemption.collateral_amount,
+            )?,
+            &[
+                vault_zbtc_account.clone(),
+                holder_zbtc_account.clone(),
+                authority_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[market.authority_bump]]],
+        )?;
+
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::SettlementRedeemed {
+            market: *market_account.key,
+            holder: *holder.key,
+            zusd_amount: redemption.zusd_amount,
+            collateral_amount: redemption.collateral_amount,
+        }
+        .emit()?;
+
+        msg!(
+            "Redeemed {} ZUSD for {} settled collateral of market {}",
+            redemption.zusd_amount,
+            redemption.collateral_amount,
+            market_account.key
+        );
+        Ok(())
+    }
+
+    fn process_configure_psm(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        fee_in_bps: u16,
+        fee_out_bps: u16,
+        ceiling: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let admin = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let reserve_mint = next_account_info(account_info_iter)?;
+
+        let mut global_config =
+            Self::load_global_config_as_admin(program_id, global_config_account, admin)?;
+
+        // Fees are in basis points of the swapped amount
+        if fee_in_bps as u64 > BPS_SCALER || fee_out_bps as u64 > BPS_SCALER {
+            return Err(ZFubaoError::InvalidFee.into());
+        }
+
+        // Only an outside stablecoin can back ZUSD 1:1
+        if *reserve_mint.key == global_config.zusd_mint
+            || *reserve_mint.key == global_config.szusd_mint
+        {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // Reserves already swapped in stay redeemable in their own mint
+        if *reserve_mint.key != global_config.psm_mint && global_config.psm_reserves != 0 {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // Swaps are converted with the decimals of the actual mint
+        global_config.psm_decimals = Self::load_mint(reserve_mint)?.decimals;
+        global_config.psm_mint = *reserve_mint.key;
+        global_config.psm_fee_in_bps = fee_in_bps;
+        global_config.psm_fee_out_bps = fee_out_bps;
+        global_config.psm_ceiling = ceiling;
+
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        msg!(
+            "PSM configured for {}: fee in {} bps, fee out {} bps, ceiling {}",
+            reserve_mint.key,
+            fee_in_bps,
+            fee_out_bps,
+            ceiling
+        );
+        Ok(())
+    }
+
+    fn process_psm_swap_in(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let user_reserve_account = next_account_info(account_info_iter)?;
+        let psm_vault = next_account_info(account_info_iter)?;
+        let user_zusd_account = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let insurance_fund = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // No new ZUSD once the protocol winds down
+        if global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        if *zusd_mint.key != global_config.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        Self::check_psm_vault(&global_config, psm_vault)?;
+        Self::check_insurance_fund(program_id, zusd_mint.key, insurance_fund)?;
+
+        global_config.psm_reserves = global_config
+            .psm_reserves
+            .checked_add(amount)
+            .filter(|reserves| *reserves <= global_config.psm_ceiling)
+            .ok_or(ZFubaoError::PsmCeilingExceeded)?;
+
+        let swap = Self::calculate_psm_swap_in(&global_config, amount)?;
+
+        // Transfer the reserve from user to the PSM vault
+        invoke(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                user_reserve_account.key,
+                psm_vault.key,
+                user.key,
+                &[],
+                amount,
+            )?,
+            &[
+                user_reserve_account.clone(),
+                psm_vault.clone(),
+                user.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        // Mint ZUSD tokens to user's account
+        invoke_signed(
+            &spl_token::instruction::mint_to(
+                token_program.key,
+                zusd_mint.key,
+                user_zusd_account.key,
+                authority_account.key,
+                &[],
+                swap.amount_out,
+            )?,
+            &[
+                zusd_mint.clone(),
+                user_zusd_account.clone(),
+                token_program.clone(),
+                authority_account.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+        )?;
+
+        // The fee is minted to the insurance fund, the reserve backs it too
+        if swap.fee > 0 {
+            invoke_signed(
+                &spl_token::instruction::mint_to(
+                    token_program.key,
+                    zusd_mint.key,
+                    insurance_fund.key,
+                    authority_account.key,
+                    &[],
+                    swap.fee,
+                )?,
+                &[
+                    zusd_mint.clone(),
+                    insurance_fund.clone(),
+                    token_program.clone(),
+                    authority_account.clone(),
+                ],
+                &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+            )?;
+        }
+
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::PsmSwappedIn {
+            user: *user.key,
+            reserve_amount: amount,
+            zusd_amount: swap.amount_out,
+            fee: swap.fee,
+        }
+        .emit()?;
+
+        msg!(
+            "Swapped {} reserve for {} ZUSD, fee {} ZUSD",
+            amount,
+            swap.amount_out,
+            swap.fee
+        );
+        Ok(())
+    }
+
+    fn process_psm_swap_out(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let user_zusd_account = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let user_reserve_account = next_account_info(account_info_iter)?;
+        let psm_vault = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let insurance_fund = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // Only burning real ZUSD releases reserves
+        if *zusd_mint.key != global_config.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        Self::check_psm_vault(&global_config, psm_vault)?;
+        Self::check_insurance_fund(program_id, zusd_mint.key, insurance_fund)?;
+
+        let swap = Self::calculate_psm_swap_out(&global_config, amount)?;
+
+        // The fee is paid in ZUSD, only the burned rest releases reserves
+        global_config.psm_reserves = global_config
+            .psm_reserves
+            .checked_sub(swap.amount_out)
+            .ok_or(ZFubaoError::InsufficientPsmReserves)?;
+
+        // Burn the ZUSD tokens
+        invoke(
+            &spl_token::instruction::burn(
+                token_program.key,
+                user_zusd_account.key,
+                zusd_mint.key,
+                user.key,
+                &[],
+                amount - swap.fee,
+            )?,
+            &[
+                user_zusd_account.clone(),
+                zusd_mint.clone(),
+                user.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        if swap.fee > 0 {
+            invoke(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    user_zusd_account.key,
+                    insurance_fund.key,
+                    user.key,
+                    &[],
+                    swap.fee,
+                )?,
+                &[
+                    user_zusd_account.clone(),
+                    insurance_fund.clone(),
+                    user.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+        }
+
+        // Transfer the reserve from the PSM vault to user
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                psm_vault.key,
+                user_reserve_account.key,
+                authority_account.key,
+                &[],
+                swap.amount_out,
+            )?,
+            &[
+                psm_vault.clone(),
+                user_reserve_account.clone(),
+                authority_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+        )?;
+
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::PsmSwappedOut {
+            user: *user.key,
+            zusd_amount: amount,
+            reserve_amount: swap.amount_out,
+            fee: swap.fee,
+        }
+        .emit()?;
+
+        msg!(
+            "Swapped {} ZUSD for {} reserve, fee {} ZUSD",
+            amount,
+            swap.amount_out,
+            swap.fee
+        );
+        Ok(())
+    }
+
+    fn process_flash_mint(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let user_zusd_account = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let instructions_sysvar = next_account_info(account_info_iter)?;
+        let insurance_fund = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // No new ZUSD once the protocol winds down
+        if global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        if *zusd_mint.key != global_config.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        Self::check_insurance_fund(program_id, zusd_mint.key, insurance_fund)?;
+
+        // One repayment must not cover two flash mints
+        if global_config.flash_mint_due != 0 {
+            return Err(ZFubaoError::FlashMintInProgress.into());
+        }
+
+        // The fee rounds up, in the protocol's favour
+        let fee = Decimal::from_u64(amount)
+            .try_mul(Decimal::from_bps(FLASH_MINT_FEE_BPS), Rounding::Up)?
+            .to_u64(Rounding::Up)?;
+        let insurance_fee = Decimal::from_u64(fee)
+            .try_mul(Decimal::from_bps(INSURANCE_FEE_SHARE_BPS), Rounding::Down)?
+            .to_u64(Rounding::Down)?;
+        global_config.flash_mint_due = amount
+            .checked_add(fee)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        Self::check_flash_mint_repaid(
+            program_id,
+            instructions_sysvar,
+            global_config.flash_mint_due,
+        )?;
+
+        // Mint ZUSD tokens to user's account
+        invoke_signed(
+            &spl_token::instruction::mint_to(
+                token_program.key,
+                zusd_mint.key,
+                user_zusd_account.key,
+                authority_account.key,
+                &[],
+                amount,
+            )?,
+            &[
+                zusd_mint.clone(),
+                user_zusd_account.clone(),
+                token_program.clone(),
+                authority_account.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+        )?;
+
+        // The insurance fund's share of the fee, the repayment burns all of it
+        if insurance_fee > 0 {
+            invoke_signed(
+                &spl_token::instruction::mint_to(
+                    token_program.key,
+                    zusd_mint.key,
+                    insurance_fund.key,
+                    authority_account.key,
+                    &[],
+                    insurance_fee,
+                )?,
+                &[
+                    zusd_mint.clone(),
+                    insurance_fund.clone(),
+                    token_program.clone(),
+                    authority_account.clone(),
+                ],
+                &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+            )?;
+        }
+
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::FlashMinted {
+            user: *user.key,
+            amount,
+            fee,
+            insurance_fee,
+        }
+        .emit()?;
+
+        msg!("Flash minted {} ZUSD, {} ZUSD due", amount, amount + fee);
+        Ok(())
+    }
+
+    fn process_flash_mint_repay(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let user_zusd_account = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // Only burning real ZUSD repays a flash mint
+        if *zusd_mint.key != global_config.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        if global_config.flash_mint_due == 0 || amount != global_config.flash_mint_due {
+            return Err(ZFubaoError::FlashMintRepayMismatch.into());
+        }
+
+        // Burn the principal and the fee
+        invoke(
+            &spl_token::instruction::burn(
+                token_program.key,
+                user_zusd_account.key,
+                zusd_mint.key,
+                user.key,
+                &[],
+                amount,
+            )?,
+            &[
+                user_zusd_account.clone(),
+                zusd_mint.clone(),
+                user.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        global_config.flash_mint_due = 0;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        msg!("Flash mint repaid with {} ZUSD", amount);
+        Ok(())
+    }
+
+    fn process_flash_borrow_zbtc(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let user_zbtc_account = next_account_info(account_info_iter)?;
+        let vault_zbtc_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let instructions_sysvar = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // Fails while a flash loan of the market is running
+        let mut market = Self::load_market(program_id, market_account)?;
+
+        // The authority owns every market's vault, so lend out of this market's only
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
+        // The fee rounds up, in the stakers' favour
+        let fee = Decimal::from_u64(amount)
+            .try_mul(Decimal::from_bps(FLASH_LOAN_FEE_BPS), Rounding::Up)?
+            .to_u64(Rounding::Up)?;
+        market.flash_loan_due = amount
+            .checked_add(fee)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        Self::check_flash_loan_repaid(
+            program_id,
+            instructions_sysvar,
+            market_account.key,
+            market.flash_loan_due,
+        )?;
+
+        // Stakers earn the fee now, the whole transaction fails without the
+        // repayment. Without stakers it stays in the vault.
+        if fee > 0 && global_config.total_staked_shares > 0 {
+            global_config.add_pool_gains(market_account.key, fee)?;
+        }
+
+        // Transfer the loan from vault to user
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                vault_zbtc_account.key,
+                user_zbtc_account.key,
+                authority_account.key,
+                &[],
+                amount,
+            )?,
+            &[
+                vault_zbtc_account.clone(),
+                user_zbtc_account.clone(),
+                authority_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[market.authority_bump]]],
+        )?;
+
+        // Locks the market and the rest of the protocol until the repayment
+        global_config.flash_loan_market = *market_account.key;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::FlashBorrowed {
+            user: *user.key,
+            market: *market_account.key,
+            amount,
+            fee,
+        }
+        .emit()?;
+
+        msg!(
+            "Flash borrowed {} collateral of market {}, {} due",
+            amount,
+            market_account.key,
+            market.flash_loan_due
+        );
+        Ok(())
+    }
+
+    fn process_flash_repay_zbtc(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let user_zbtc_account = next_account_info(account_info_iter)?;
+        let vault_zbtc_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_locked_global_config(program_id, global_config_account)?;
+        let mut market = Self::load_locked_market(program_id, market_account)?;
+
+        // Collateral only counts if it actually lands in the market's vault
+        Self::check_market_vault(&market, vault_zbtc_account)?;
+
+        if market.flash_loan_due == 0
+            || amount != market.flash_loan_due
+            || global_config.flash_loan_market != *market_account.key
+        {
+            return Err(ZFubaoError::FlashLoanRepayMismatch.into());
+        }
+
+        // Transfer the principal and the fee from user to vault
+        invoke(
+            &spl_token::instruction::transfer(
+                token_program.key,
+                user_zbtc_account.key,
+                vault_zbtc_account.key,
+                user.key,
+                &[],
+                amount,
+            )?,
+            &[
+                user_zbtc_account.clone(),
+                vault_zbtc_account.clone(),
+                user.clone(),
+                token_program.clone(),
+            ],
+        )?;
+
+        // Unlocks the market and the protocol
+        market.flash_loan_due = 0;
+        global_config.flash_loan_market = Pubkey::default();
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        msg!(
+            "Flash loan of market {} repaid with {} collateral",
+            market_account.key,
+            amount
+        );
+        Ok(())
+    }
+
+    fn process_deleverage(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        collateral_amount: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let collateral_market_account = next_account_info(account_info_iter)?;
+        let staking_vault = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // Settlement prices every position once, nothing changes hands after it
+        if global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        // Load obligation data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        // Selling collateral is a withdrawal, so it takes full permission
+        if !obligation.can_be_operated_by(user.key, DelegatePermission::Full) {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
+
+        // Load the market the obligation lives in
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+
+        // Only burning real ZUSD out of the staking vault cancels debt
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        if *staking_vault.key
+            != get_associated_token_address(&global_config.authority, &global_config.zusd_mint)
+        {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
+
+        let deposit_market_accounts = account_info_iter.as_slice();
+        let markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            deposit_market_accounts,
+        )?;
+
+        // The sold collateral has to be one of the obligation's deposits
+        if obligation.deposited(collateral_market_account.key) == 0 {
+            return Err(ZFubaoError::DepositMarketMismatch.into());
+        }
+        let (_, collateral_market) = markets
+            .iter()
+            .find(|(key, _)| key == collateral_market_account.key)
+            .ok_or(ZFubaoError::DepositMarketMismatch)?;
+
+        // Gains need stakers to go to, and debt needs ZUSD to cancel it
+        let vault_balance = spl_token::state::Account::unpack(&staking_vault.data.borrow())?.amount;
+        let available_zusd = global_config.stability_pool_capacity(vault_balance);
+        if available_zusd == 0 || global_config.total_staked_shares == 0 {
+            return Err(ZFubaoError::StabilityPoolEmpty.into());
+        }
+
+        let deleveraging = Self::calculate_deleverage(
+            &obligation,
+            collateral_market_account.key,
+            collateral_market,
+            &market,
+            collateral_amount,
+            available_zusd,
+        )?;
+        if deleveraging.zusd_amount == 0 {
+            return Err(ZFubaoError::NothingToDeleverage.into());
+        }
+
+        // Update obligation state
+        let health_before = Self::calculate_health_factor(&obligation, &markets)?;
+        obligation.zusd_borrowed = obligation
+            .zusd_borrowed
+            .checked_sub(deleveraging.zusd_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        obligation.remove_deposit(
+            collateral_market_account.key,
+            deleveraging.collateral_amount,
+        )?;
+
+        // With an LTV near the 1% fee each sale takes more off the borrow limit
+        // than off the debt. Such a sale would only dodge the liquidation bonus.
+        if obligation.zusd_borrowed != 0
+            && Self::calculate_health_factor(&obligation, &markets)? <= health_before
+        {
+            return Err(ZFubaoError::HealthNotImproved.into());
+        }
+        market.remove_borrowed(deleveraging.zusd_amount)?;
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        // The collateral market may be the market of the obligation, so load it
+        // after that is saved. The sold collateral stays in its vault for the
+        // stakers to claim.
+        let mut collateral_market = Self::load_market(program_id, collateral_market_account)?;
+        collateral_market.remove_deposits(deleveraging.collateral_amount)?;
+        collateral_market.serialize(&mut &mut collateral_market_account.data.borrow_mut()[..])?;
+
+        // Stakers give up the burned ZUSD for the collateral
+        global_config.write_down_szusd_price(vault_balance, deleveraging.zusd_amount)?;
+        global_config.add_pool_gains(
+            collateral_market_account.key,
+            deleveraging.collateral_amount,
+        )?;
+
+        invoke_signed(
+            &spl_token::instruction::burn(
+                token_program.key,
+                staking_vault.key,
+                zusd_mint.key,
+                authority_account.key,
+                &[],
+                deleveraging.zusd_amount,
+            )?,
+            &[
+                staking_vault.clone(),
+                zusd_mint.clone(),
+                authority_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+        )?;
+
+        // Save updated obligation and global config data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        // The SZUSD market follows the deposit markets, keep its price in step
+        let szusd_market_account = deposit_market_accounts
+            .get(markets.len() - 1)
+            .ok_or(ProgramError::NotEnoughAccountKeys)?;
+        Self::sync_szusd_market(program_id, szusd_market_account, &global_config)?;
+
+        ZFubaoEvent::ObligationDeleveraged {
+            obligation: *obligation_account.key,
+            market: *collateral_market_account.key,
+            zusd_amount: deleveraging.zusd_amount,
+            collateral_amount: deleveraging.collateral_amount,
+            fee: deleveraging.fee,
+        }
+        .emit()?;
+
+        msg!(
+            "Deleveraged {} ZUSD of debt for {} collateral of market {}",
+            deleveraging.zusd_amount,
+            deleveraging.collateral_amount,
+            collateral_market_account.key
+        );
+        Ok(())
+    }
+
+    fn process_set_trigger(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        threshold_bps: u16,
+        target_bps: u16,
+        keeper_tip: u64,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let trigger_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
+
+        // Load obligation data
+        let obligation = Self::load_obligation(program_id, obligation_account)?;
+
+        // Executions sell collateral, so setting them up takes full permission
+        if !obligation.can_be_operated_by(user.key, DelegatePermission::Full) {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
+
+        // Triggers can only sell collateral of an existing market
+        Self::load_market(program_id, market_account)?;
+
+        // Executions have to leave the obligation healthier than they found it
+        if threshold_bps < BPS_SCALER as u16 || target_bps <= threshold_bps {
+            return Err(ZFubaoError::InvalidTrigger.into());
+        }
+
+        // Derive PDA for trigger
+        let (pda, bump_seed) = find_trigger_pda(obligation_account.key, program_id);
+
+        // Verify trigger account is the PDA
+        if *trigger_account.key != pda {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // Replacing a trigger keeps its account
+        if trigger_account.owner != program_id {
+            msg!("Create trigger account");
+
+            Self::create_pda_account(
+                user,
+                trigger_account,
+                system_program,
+                program_id,
+                Trigger::LEN,
+                &[TRIGGER_SEED, obligation_account.key.as_ref(), &[bump_seed]],
+            )?;
+        }
+
+        let trigger = Trigger {
+            obligation: *obligation_account.key,
+            owner: obligation.owner,
+            market: *market_account.key,
+            bump: bump_seed,
+            threshold_bps,
+            target_bps,
+            keeper_tip,
+        };
+        trigger.serialize(&mut &mut trigger_account.data.borrow_mut()[..])?;
+
+        ZFubaoEvent::TriggerSet {
+            obligation: *obligation_account.key,
+            market: *market_account.key,
+            threshold_bps,
+            target_bps,
+            keeper_tip,
+        }
+        .emit()?;
+
+        msg!(
+            "Trigger of obligation {} set to restore health {} bps below {} bps",
+            obligation_account.key,
+            target_bps,
+            threshold_bps
+        );
+        Ok(())
+    }
+
+    fn process_cancel_trigger(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let user = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let trigger_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !user.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Fails while a flash loan is running
+        Self::load_global_config(program_id, global_config_account)?;
+
+        // Load obligation and trigger data
+        let obligation = Self::load_obligation(program_id, obligation_account)?;
+        let trigger = Self::load_trigger(program_id, trigger_account)?;
+
+        if trigger.obligation != *obligation_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if !obligation.can_be_operated_by(user.key, DelegatePermission::Full) {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
+
+        Self::close_trigger(program_id, obligation_account.key, trigger_account, user)?;
+
+        msg!("Trigger of obligation {} cancelled", obligation_account.key);
+        Ok(())
+    }
+
+    fn process_execute_trigger(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+
+        let keeper = next_account_info(account_info_iter)?;
+        let authority_account = next_account_info(account_info_iter)?;
+        let global_config_account = next_account_info(account_info_iter)?;
+        let market_account = next_account_info(account_info_iter)?;
+        let obligation_account = next_account_info(account_info_iter)?;
+        let trigger_account = next_account_info(account_info_iter)?;
+        let collateral_market_account = next_account_info(account_info_iter)?;
+        let staking_vault = next_account_info(account_info_iter)?;
+        let zusd_mint = next_account_info(account_info_iter)?;
+        let keeper_collateral_account = next_account_info(account_info_iter)?;
+        let vault_collateral_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+
+        // Check signer
+        if !keeper.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let mut global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        // Settlement prices every position once, nothing changes hands after it
+        if global_config.is_settled() {
+            return Err(ZFubaoError::ProtocolSettled.into());
+        }
+
+        // Load obligation and trigger data
+        let mut obligation = Self::load_obligation(program_id, obligation_account)?;
+        let trigger = Self::load_trigger(program_id, trigger_account)?;
+
+        if trigger.obligation != *obligation_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        // A trigger only stands for the owner that set it
+        if trigger.owner != obligation.owner {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
+        if trigger.market != *collateral_market_account.key {
+            return Err(ZFubaoError::DepositMarketMismatch.into());
+        }
+
+        // Load the market the obligation lives in
+        let mut market = Self::load_obligation_market(program_id, market_account, &obligation)?;
+
+        // Only burning real ZUSD out of the staking vault cancels debt
+        if *zusd_mint.key != market.zusd_mint {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+        if *staking_vault.key
+            != get_associated_token_address(&global_config.authority, &global_config.zusd_mint)
+        {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
+
+        let deposit_market_accounts = account_info_iter.as_slice();
+        let markets = Self::load_deposit_markets(
+            program_id,
+            &obligation,
+            market_account,
+            &market,
+            deposit_market_accounts,
+        )?;
+
+        // The sold collateral has to be one of the obligation's deposits
+        if obligation.deposited(collateral_market_account.key) == 0 {
+            return Err(ZFubaoError::DepositMarketMismatch.into());
+        }
+        let (_, collateral_market) = markets
+            .iter()
+            .find(|(key, _)| key == collateral_market_account.key)
+            .ok_or(ZFubaoError::DepositMarketMismatch)?;
+
+        // The tip is paid out of this market's vault only
+        Self::check_market_vault(collateral_market, vault_collateral_account)?;
+
+        // Only positions below the threshold at the current prices qualify
+        if obligation.zusd_borrowed == 0
+            || Self::calculate_health_factor(&obligation, &markets)?
+                >= Decimal::from_bps(trigger.threshold_bps as u64)
+        {
+            return Err(ZFubaoError::TriggerNotReached.into());
+        }
+
+        // Gains need stakers to go to, and debt needs ZUSD to cancel it
+        let vault_balance = spl_token::state::Account::unpack(&staking_vault.data.borrow())?.amount;
+        let available_zusd = global_config.stability_pool_capacity(vault_balance);
+        if available_zusd == 0 || global_config.total_staked_shares == 0 {
+            return Err(ZFubaoError::StabilityPoolEmpty.into());
+        }
+
+        // The tip comes off the deposit first, the sale makes up for it
+        let full_tip = trigger
+            .keeper_tip
+            .min(obligation.deposited(collateral_market_account.key));
+        obligation.remove_deposit(collateral_market_account.key, full_tip)?;
+
+        let deleveraging = Self::calculate_trigger_deleverage(
+            &obligation,
+            &markets,
+            collateral_market_account.key,
+            trigger.target_bps,
+            available_zusd,
+        )?;
+        if deleveraging.zusd_amount == 0 {
+            return Err(ZFubaoError::NothingToDeleverage.into());
+        }
+
+        // A sale the pool cuts short only earns its share of the tip, so splitting
+        // one deleverage into many executions pays no more than one
+        let full_deleveraging = Self::calculate_trigger_deleverage(
+            &obligation,
+            &markets,
+            collateral_market_account.key,
+            trigger.target_bps,
+            u64::MAX,
+        )?;
+        let keeper_tip = Self::calculate_keeper_tip(
+            full_tip,
+            deleveraging.zusd_amount,
+            full_deleveraging.zusd_amount,
+        )?;
+        obligation.add_deposit(collateral_market_account.key, full_tip - keeper_tip)?;
+
+        // Update obligation state
+        obligation.zusd_borrowed = obligation
+            .zusd_borrowed
+            .checked_sub(deleveraging.zusd_amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        obligation.remove_deposit(
+            collateral_market_account.key,
+            deleveraging.collateral_amount,
+        )?;
+        market.remove_borrowed(deleveraging.zusd_amount)?;
+        Self::update_redemption_bucket(&mut obligation, market_account.key, &mut market)?;
+        market.serialize(&mut &mut market_account.data.borrow_mut()[..])?;
+
+        // The collateral market may be the market of the obligation, so load it
+        // after that is saved. The sold collateral stays in its vault for the
+        // stakers to claim.
+        let mut collateral_market = Self::load_market(program_id, collateral_market_account)?;
+        collateral_market.remove_deposits(
+            deleveraging
+                .collateral_amount
+                .checked_add(keeper_tip)
+                .ok_or(ProgramError::ArithmeticOverflow)?,
+        )?;
+        collateral_market.serialize(&mut &mut collateral_market_account.data.borrow_mut()[..])?;
+
+        // Stakers give up the burned ZUSD for the collateral
+        global_config.write_down_szusd_price(vault_balance, deleveraging.zusd_amount)?;
+        global_config.add_pool_gains(
+            collateral_market_account.key,
+            deleveraging.collateral_amount,
+        )?;
+
+        invoke_signed(
+            &spl_token::instruction::burn(
+                token_program.key,
+                staking_vault.key,
+                zusd_mint.key,
+                authority_account.key,
+                &[],
+                deleveraging.zusd_amount,
+            )?,
+            &[
+                staking_vault.clone(),
+                zusd_mint.clone(),
+                authority_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[AUTHORITY_SEED, &[global_config.authority_bump]]],
+        )?;
+
+        // Transfer the tip from vault to keeper
+        if keeper_tip > 0 {
+            invoke_signed(
+                &spl_token::instruction::transfer(
+                    token_program.key,
+                    vault_collateral_account.key,
+                    keeper_collateral_account.key,
+                    authority_account.key,
+                    &[],
+                    keeper_tip,
+                )?,
+                &[
+                    vault_collateral_account.clone(),
+                    keeper_collateral_account.clone(),
+                    authority_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[AUTHORITY_SEED, &[collateral_market.authority_bump]]],
+            )?;
+        }
+
+        // Save updated obligation and global config data
+        obligation.serialize(&mut &mut obligation_account.data.borrow_mut()[..])?;
+        global_config.serialize(&mut &mut global_config_account.data.borrow_mut()[..])?;
+
+        // The SZUSD market follows the deposit markets, keep its price in step
+        let szusd_market_account = deposit_market_accounts
+            .get(markets.len() - 1)
+            .ok_or(ProgramError::NotEnoughAccountKeys)?;
+        Self::sync_szusd_market(program_id, szusd_market_account, &global_config)?;
+
+        ZFubaoEvent::TriggerExecuted {
+            obligation: *obligation_account.key,
+            keeper: *keeper.key,
+            zusd_amount: deleveraging.zusd_amount,
+            collateral_amount: deleveraging.collateral_amount,
+            keeper_tip,
+        }
+        .emit()?;
+
+        msg!(
+            "Trigger deleveraged {} ZUSD of debt for {} collateral, {} tip to keeper {}",
+            deleveraging.zusd_amount,
+            deleveraging.collateral_amount,
+            keeper_tip,
+            keeper.key
+        );
+        Ok(())
+    }
+
+    // Helper function to move an obligation into the redemption bucket of its
+    // collateral and debt in `market`. Only the market its debt lives in counts
+    // it, any other market is left alone.
+    fn update_redemption_bucket(
+        obligation: &mut Obligation,
+        market_key: &Pubkey,
+        market: &mut Market,
+    ) -> ProgramResult {
+        if obligation.market != *market_key {
+            return Ok(());
+        }
+
+        let bucket =
+            market.redemption_bucket(obligation.deposited(market_key), obligation.zusd_borrowed)?;
+        if bucket == obligation.redemption_bucket {
+            return Ok(());
+        }
+
+        if let Some(count) = market
+            .redemption_buckets
+            .get_mut(obligation.redemption_bucket as usize)
+        {
+            *count = count
+                .checked_sub(1)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+        }
+        if let Some(count) = market.redemption_buckets.get_mut(bucket as usize) {
+            *count = count
+                .checked_add(1)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+        }
+        obligation.redemption_bucket = bucket;
+
+        Ok(())
+    }
+
+    // Helper function to scale the keeper tip to the part of the deleverage that
+    // was done, rounded down
+    pub fn calculate_keeper_tip(
+        keeper_tip: u64,
+        zusd_amount: u64,
+        full_zusd_amount: u64,
+    ) -> Result<u64, ProgramError> {
+        if zusd_amount >= full_zusd_amount {
+            return Ok(keeper_tip);
+        }
+
+        u64::try_from(keeper_tip as u128 * zusd_amount as u128 / full_zusd_amount as u128)
+            .map_err(|_| ProgramError::ArithmeticOverflow)
+    }
+
+    // Helper function to summarize how safe an obligation is. `markets` starts
+    // with the queried market, see calculate_position_status.
+    pub fn calculate_obligation_health(
+        obligation: &Obligation,
+        markets: &[(Pubkey, Market)],
+    ) -> Result<ObligationHealth, ProgramError> {
+        let (market_key, market) = Self::queried_market(markets)?;
+        let (collateral_value, borrow_limit_value) =
+            Self::calculate_collateral_value(obligation, markets, None)?;
+        let debt_value = market.zusd_value(obligation.zusd_borrowed)?;
+        let position = Self::calculate_position_status(obligation, markets)?;
+
+        let (health_factor, liquidation_price) = if debt_value.is_zero() {
+            (u128::MAX, 0)
+        } else {
+            let health_factor = borrow_limit_value
+                .try_div(debt_value, Rounding::Down)?
+                .to_scaled_val()?;
+
+            // Price where collateral * price * ltv covers what the other deposits do not
+            let (_, other_limit_value) =
+                Self::calculate_collateral_value(obligation, markets, Some(market_key))?;
+            let uncovered_debt = debt_value.saturating_sub(other_limit_value);
+            let collateral = Decimal::from_token_amount(
+                obligation.deposited(market_key),
+                market.collateral_decimals,
+            )?
+            .try_mul(market.ltv(), Rounding::Down)?;
+            let liquidation_price = if uncovered_debt.is_zero() {
+                0
+            } else if collateral.is_zero() {
+                u128::MAX
+            } else {
+                uncovered_debt
+                    .try_div(collateral, Rounding::Up)?
+                    .to_scaled_val()?
+            };
+
+            (health_factor, liquidation_price)
+        };
+
+        Ok(ObligationHealth {
+            collateral_value: collateral_value.to_scaled_val()?,
+            debt_value: debt_value.to_scaled_val()?,
+            health_factor,
+            max_borrowable: position.max_borrowable,
+            max_withdrawable: position.max_withdrawable,
+            liquidation_price,
+        })
+    }
+
+    // Helper function to calculate the total ZUSD debt the collateral can back,
+    // the sum of every deposit's value weighted by its market's LTV
+    pub fn calculate_borrow_limit(
+        obligation: &Obligation,
+        markets: &[(Pubkey, Market)],
+    ) -> Result<u64, ProgramError> {
+        let (_, market) = Self::queried_market(markets)?;
+        let (_, max_debt_value) = Self::calculate_collateral_value(obligation, markets, None)?;
+
+        // Borrow capacity rounds down
+        market.zusd_amount(max_debt_value, Rounding::Down)
+    }
+
+    // Helper function to work out the headroom left on an obligation. An
+    // undercollateralized position has no headroom rather than a negative one.
+    //
+    // `markets` holds the market of every deposit. The first one is the queried
+    // market: max_withdrawable is in its collateral, and it values the ZUSD debt.
+    pub fn calculate_position_status(
+        obligation: &Obligation,
+        markets: &[(Pubkey, Market)],
+    ) -> Result<PositionStatus, ProgramError> {
+        let (market_key, market) = Self::queried_market(markets)?;
+        let borrow_limit = Self::calculate_borrow_limit(obligation, markets)?;
+
+        if obligation.zusd_borrowed > borrow_limit {
+            return Ok(PositionStatus {
+                state: CollateralState::Undercollateralized,
+                borrow_limit,
+                max_borrowable: 0,
+                max_withdrawable: 0,
+            });
+        }
+
+        // Calculate the collateral of the queried market still needed to back the
+        // debt the other deposits cannot. Healthy debt implies that any uncovered
+        // part comes with a non-zero LTV and price, so the divisions are safe.
+        let (_, other_limit_value) =
+            Self::calculate_collateral_value(obligation, markets, Some(market_key))?;
+        let uncovered_debt = market
+            .zusd_value(obligation.zusd_borrowed)?
+            .saturating_sub(other_limit_value);
+        let min_collateral = if uncovered_debt.is_zero() {
+            0
+        } else {
+            let min_collateral_value = uncovered_debt.try_div(market.ltv(), Rounding::Up)?;
+
+            // Convert back to collateral, required collateral rounds up
+            market.collateral_amount(min_collateral_value, Rounding::Up)?
+        };
+
+        Ok(PositionStatus {
+            state: CollateralState::Healthy,
+            borrow_limit,
+            max_borrowable: borrow_limit - obligation.zusd_borrowed,
+            max_withdrawable: obligation
+                .deposited(market_key)
+                .saturating_sub(min_collateral),
+        })
+    }
+
+    // Helper function to split off the part of a redemption one obligation can
+    // take from its deposit in `market`. It sheds no more than its debt and
+    // collateral worth no more than the deposit, both rounded in its favour. The
+    // fee is taken off the collateral paid out.
+    pub fn calculate_redemption(
+        obligation: &Obligation,
+        market_key: &Pubkey,
+        market: &Market,
+        zusd_amount: u64,
+    ) -> Result<Redemption, ProgramError> {
+        // The insurance fund's share of the fee is paid in ZUSD on top of the debt,
+        // so `zusd_amount` has to cover both
+        let insurance_rate = Decimal::from_bps(REDEMPTION_FEE_BPS)
+            .try_mul(Decimal::from_bps(INSURANCE_FEE_SHARE_BPS), Rounding::Down)?;
+        let deposit_value = market.collateral_value(obligation.deposited(market_key))?;
+        let zusd_amount = Decimal::from_u64(zusd_amount)
+            .try_div(Decimal::one().try_add(insurance_rate)?, Rounding::Down)?
+            .to_u64(Rounding::Down)?
+            .min(obligation.zusd_borrowed)
+            .min(market.zusd_amount(deposit_value, Rounding::Down)?);
+        let insurance_fee = Decimal::from_u64(zusd_amount)
+            .try_mul(insurance_rate, Rounding::Down)?
+            .to_u64(Rounding::Down)?;
+
+        let collateral_amount =
+            market.collateral_amount(market.zusd_value(zusd_amount)?, Rounding::Down)?;
+        let fee = Decimal::from_u64(collateral_amount)
+            .try_mul(Decimal::from_bps(REDEMPTION_FEE_BPS), Rounding::Up)?
+            .to_u64(Rounding::Up)?;
+
+        // The redeemer buys the insurance fund's share of the fee at face value
+        let insurance_collateral =
+            market.collateral_amount(market.zusd_value(insurance_fee)?, Rounding::Down)?;
+        let fee = fee
+            .checked_sub(insurance_collateral)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        Ok(Redemption {
+            zusd_amount,
+            insurance_fee,
+            collateral_amount: collateral_amount - fee,
+            fee,
+        })
+    }
+
+    // Helper function to work out how much debt the stability pool cancels in one
+    // liquidation and the collateral it seizes for it. It takes no more than the
+    // debt, the ZUSD in the pool, or what the deposit covers with the bonus.
+    pub fn calculate_liquidation(
+        obligation: &Obligation,
+        collateral_market_key: &Pubkey,
+        collateral_market: &Market,
+        market: &Market,
+        available_zusd: u64,
+    ) -> Result<Liquidation, ProgramError> {
+        let bonus = Decimal::one().try_add(Decimal::from_bps(LIQUIDATION_BONUS_BPS))?;
+        let deposit_value =
+            collateral_market.collateral_value(obligation.deposited(collateral_market_key))?;
+        let zusd_amount = obligation
+            .zusd_borrowed
+            .min(available_zusd)
+            .min(market.zusd_amount(
+                deposit_value.try_div(bonus, Rounding::Down)?,
+                Rounding::Down,
+            )?);
+
+        // Seized collateral rounds down, in the obligation's favour
+        let collateral_amount = collateral_market.collateral_amount(
+            market
+                .zusd_value(zusd_amount)?
+                .try_mul(bonus, Rounding::Down)?,
+            Rounding::Down,
+        )?;
+
+        Ok(Liquidation {
+            zusd_amount,
+            collateral_amount,
+        })
+    }
+
+    // Helper function to work out how much debt the stability pool cancels for
+    // up to `collateral_amount` of a deposit at the oracle price less the fee.
+    // When the debt or the ZUSD in the pool runs out first, only the collateral
+    // paying for it is sold.
+    pub fn calculate_deleverage(
+        obligation: &Obligation,
+        collateral_market_key: &Pubkey,
+        collateral_market: &Market,
+        market: &Market,
+        collateral_amount: u64,
+        available_zusd: u64,
+    ) -> Result<Deleveraging, ProgramError> {
+        let discount = Decimal::one().try_sub(Decimal::from_bps(DELEVERAGE_FEE_BPS))?;
+        let collateral_amount = collateral_amount.min(obligation.deposited(collateral_market_key));
+        let zusd_amount = market
+            .zusd_amount(
+                collateral_market
+                    .collateral_value(collateral_amount)?
+                    .try_mul(discount, Rounding::Down)?,
+                Rounding::Down,
+            )?
+            .min(obligation.zusd_borrowed)
+            .min(available_zusd);
+
+        // Sold collateral rounds up, in the stakers' favour
+        let zusd_value = market.zusd_value(zusd_amount)?;
+        let collateral_amount = collateral_market
+            .collateral_amount(zusd_value.try_div(discount, Rounding::Up)?, Rounding::Up)?
+            .min(collateral_amount);
+        let fee = collateral_amount
+            .saturating_sub(collateral_market.collateral_amount(zusd_value, Rounding::Up)?);
+
+        Ok(Deleveraging {
+            zusd_amount,
+            collateral_amount,
+            fee,
+        })
+    }
+
+    // Helper function to work out the deleverage that brings an obligation back
+    // to `target_bps` of health. Each USD of collateral sold cancels 99 cents of
+    // debt and takes its LTV off the borrow limit, so the sale solves
+    // limit - ltv * sold = target * (debt - 0.99 * sold).
+    pub fn calculate_trigger_deleverage(
+        obligation: &Obligation,
+        markets: &[(Pubkey, Market)],
+        collateral_market_key: &Pubkey,
+        target_bps: u16,
+        available_zusd: u64,
+    ) -> Result<Deleveraging, ProgramError> {
+        let (_, market) = Self::queried_market(markets)?;
+        let (_, collateral_market) = markets
+            .iter()
+            .find(|(key, _)| key == collateral_market_key)
+            .ok_or(ZFubaoError::DepositMarketMismatch)?;
+        let (_, borrow_limit_value) = Self::calculate_collateral_value(obligation, markets, None)?;
+
+        let target = Decimal::from_bps(target_bps as u64);
+        let discount = Decimal::one().try_sub(Decimal::from_bps(DELEVERAGE_FEE_BPS))?;
+        let shortfall = target
+            .try_mul(market.zusd_value(obligation.zusd_borrowed)?, Rounding::Up)?
+            .saturating_sub(borrow_limit_value);
+        let gain_per_value = target
+            .try_mul(discount, Rounding::Down)?
+            .saturating_sub(collateral_market.ltv());
+
+        // Near a 100% LTV selling barely helps, so sell all it takes to clear the debt
+        let collateral_amount = if gain_per_value.is_zero() {
+            u64::MAX
+        } else {
+            collateral_market.collateral_amount(
+                shortfall.try_div(gain_per_value, Rounding::Up)?,
+                Rounding::Up,
+            )?
+        };
+
+        Self::calculate_deleverage(
+            obligation,
+            collateral_market_key,
+            collateral_market,
+            market,
+            collateral_amount,
+            available_zusd,
+        )
+    }
+
+    // ZUSD a bid of up to `amount` pays at `now` and the collateral it buys. The
+    // bid never pays more than `debt` plus the penalty or than the collateral
+    // left is worth.
+    pub fn calculate_auction_fill(
+        auction: &Auction,
+        collateral_market: &Market,
+        debt: u64,
+        amount: u64,
+        now: i64,
+    ) -> Result<AuctionFill, ProgramError> {
+        let price = auction.current_price(now)?;
+        let with_penalty = Decimal::one().try_add(Decimal::from_bps(AUCTION_PENALTY_BPS))?;
+        let debt_with_penalty = Decimal::from_u64(debt)
+            .try_mul(with_penalty, Rounding::Up)?
+            .to_u64(Rounding::Up)?;
+
+        // Buying out the auction costs the collateral left, rounded up
+        let remaining_value = Decimal::from_token_amount(
+            auction.collateral_amount,
+            collateral_market.collateral_decimals,
+        )?
+        .try_mul(price, Rounding::Up)?;
+        let buyout_amount = collateral_market.zusd_amount(remaining_value, Rounding::Up)?;
+
+        let zusd_amount = amount.min(debt_with_penalty).min(buyout_amount);
+
+        // The debt repaid rounds up and the penalty down, in the obligation's favour
+        let repaid = Decimal::from_u64(zusd_amount)
+            .try_div(with_penalty, Rounding::Up)?
+            .to_u64(Rounding::Up)?
+            .min(debt);
+        let penalty = zusd_amount - repaid;
+
+        if zusd_amount == buyout_amount {
+            return Ok(AuctionFill {
+                zusd_amount,
+                collateral_amount: auction.collateral_amount,
+                penalty,
+            });
+        }
+
+        // Partial fills round the collateral down, in the obligation's favour
+        let collateral_amount = collateral_market
+            .zusd_value(zusd_amount)?
+            .try_div(price, Rounding::Down)?
+            .to_token_amount(collateral_market.collateral_decimals, Rounding::Down)?
+            .min(auction.collateral_amount);
+
+        Ok(AuctionFill {
+            zusd_amount,
+            collateral_amount,
+            penalty,
+        })
+    }
+
+    // ZUSD a settlement redemption of up to `amount` burns and the collateral of
+    // `market` it pays at the settlement rate. It burns no more than the settled
+    // debt, and less when the market's settled collateral runs out.
+    pub fn calculate_settlement_redemption(
+        global_config: &ZFubaoConfig,
+        market: &Market,
+        amount: u64,
+    ) -> Result<SettlementRedemption, ProgramError> {
+        let rate = global_config.settlement_rate(market.zusd_decimals)?;
+        let zusd_amount = amount.min(global_config.settlement_debt);
+        if market.settled_collateral == 0 || rate.is_zero() {
+            return Ok(SettlementRedemption {
+                zusd_amount: 0,
+                collateral_amount: 0,
+                value: Decimal::zero(),
+            });
+        }
+
+        // Payouts round down, in the favour of the holders still to redeem
+        let value = market
+            .zusd_value(zusd_amount)?
+            .try_mul(rate, Rounding::Down)?;
+        let collateral_amount = market.collateral_amount(value, Rounding::Down)?;
+        if collateral_amount <= market.settled_collateral {
+            return Ok(SettlementRedemption {
+                zusd_amount,
+                collateral_amount,
+                value,
+            });
+        }
+
+        // The market pays out what it holds for the ZUSD that is worth, rounded up
+        let value = market
+            .collateral_value(market.settled_collateral)?
+            .min(Decimal::from_scaled_val(global_config.settlement_value));
+        let zusd_amount = market
+            .zusd_amount(value.try_div(rate, Rounding::Up)?, Rounding::Up)?
+            .min(zusd_amount);
+
+        Ok(SettlementRedemption {
+            zusd_amount,
+            collateral_amount: market.settled_collateral,
+            value,
+        })
+    }
+
+    // ZUSD a PSM swap in of `amount` raw reserve mints, 1:1 less the fee in
+    pub fn calculate_psm_swap_in(
+        global_config: &ZFubaoConfig,
+        amount: u64,
+    ) -> Result<PsmSwap, ProgramError> {
+        let zusd_amount = Decimal::from_token_amount(amount, global_config.psm_decimals)?
+            .to_token_amount(global_config.zusd_decimals, Rounding::Down)?;
+        Self::calculate_psm_fee(zusd_amount, global_config.psm_fee_in_bps)
+    }
+
+    // Reserve a PSM swap out of `amount` raw ZUSD releases, 1:1 less the fee out.
+    // The fee is taken in ZUSD, so it never leaves the reserves behind.
+    pub fn calculate_psm_swap_out(
+        global_config: &ZFubaoConfig,
+        amount: u64,
+    ) -> Result<PsmSwap, ProgramError> {
+        let swap = Self::calculate_psm_fee(amount, global_config.psm_fee_out_bps)?;
+        let reserve_amount =
+            Decimal::from_token_amount(swap.amount_out, global_config.zusd_decimals)?
+                .to_token_amount(global_config.psm_decimals, Rounding::Down)?;
+
+        Ok(PsmSwap {
+            amount_out: reserve_amount,
+            fee: swap.fee,
+        })
+    }
+
+    // The fee rounds up, in the PSM's favour
+    fn calculate_psm_fee(amount: u64, fee_bps: u16) -> Result<PsmSwap, ProgramError> {
+        let fee = Decimal::from_u64(amount)
+            .try_mul(Decimal::from_bps(fee_bps as u64), Rounding::Up)?
+            .to_u64(Rounding::Up)?;
+
+        Ok(PsmSwap {
+            amount_out: amount - fee,
+            fee,
+        })
+    }
+
+    // Helper function to compare how risky obligations are, the borrow limit of
+    // all their collateral over their debt
+    fn calculate_health_factor(
+        obligation: &Obligation,
+        markets: &[(Pubkey, Market)],
+    ) -> Result<Decimal, ProgramError> {
+        let (_, market) = Self::queried_market(markets)?;
+        let (_, borrow_limit_value) = Self::calculate_collateral_value(obligation, markets, None)?;
+
+        borrow_limit_value.try_div(market.zusd_value(obligation.zusd_borrowed)?, Rounding::Down)
+    }
+
+    // Helper function to check that the obligation's debt is within the LTV limit
+    fn check_ltv(obligation: &Obligation, markets: &[(Pubkey, Market)]) -> ProgramResult {
+        let position = Self::calculate_position_status(obligation, markets)?;
+
+        if position.is_undercollateralized() {
+            msg!(
+                "Debt {} exceeds borrow limit {}",
+                obligation.zusd_borrowed,
+                position.borrow_limit
+            );
+            return Err(ZFubaoError::BorrowLimitExceeded.into());
+        }
+
+        Ok(())
+    }
+
+    // Helper function to sum the USD value of an obligation's deposits and the debt
+    // they can back, optionally leaving out the deposit in one market
+    fn calculate_collateral_value(
+        obligation: &Obligation,
+        markets: &[(Pubkey, Market)],
+        excluded_market: Option<&Pubkey>,
+    ) -> Result<(Decimal, Decimal), ProgramError> {
+        let mut collateral_value = Decimal::zero();
+        let mut borrow_limit_value = Decimal::zero();
+
+        for deposit in obligation.deposits() {
+            if Some(&deposit.market) == excluded_market {
+                continue;
+            }
+
+            let (_, market) = markets
+                .iter()
+                .find(|(key, _)| *key == deposit.market)
+                .ok_or(ZFubaoError::DepositMarketMismatch)?;
+
+            let value = market.collateral_value(deposit.amount)?;
+            collateral_value = collateral_value.try_add(value)?;
+            borrow_limit_value =
+                borrow_limit_value.try_add(value.try_mul(market.ltv(), Rounding::Down)?)?;
+        }
+
+        Ok((collateral_value, borrow_limit_value))
+    }
+
+    fn queried_market(markets: &[(Pubkey, Market)]) -> Result<(&Pubkey, &Market), ProgramError> {
+        markets
+            .first()
+            .map(|(key, market)| (key, market))
+            .ok_or(ProgramError::NotEnoughAccountKeys)
+    }
+
+    // Helper function to create a PDA owned by this program. Handles accounts that
+    // were pre-funded (e.g. lamports sent to a closed obligation address).
+    fn create_pda_account<'a>(
+        payer: &AccountInfo<'a>,
+        new_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        program_id: &Pubkey,
+        space: usize,
+        signer_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let rent = Rent::get()?;
+        let required_lamports = rent.minimum_balance(space);
+        let current_lamports = new_account.lamports();
+
+        if current_lamports == 0 {
+            return invoke_signed(
+                &system_instruction::create_account(
+                    payer.key,
+                    new_account.key,
+                    required_lamports,
+                    space as u64,
+                    program_id,
+                ),
+                &[payer.clone(), new_account.clone(), system_program.clone()],
+                &[signer_seeds],
+            );
+        }
+
+        // create_account refuses accounts that already hold lamports, so top up,
+        // allocate and assign instead
+        let shortfall = required_lamports.saturating_sub(current_lamports);
+        if shortfall > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, new_account.key, shortfall),
+                &[payer.clone(), new_account.clone(), system_program.clone()],
+            )?;
+        }
+
+        invoke_signed(
+            &system_instruction::allocate(new_account.key, space as u64),
+            &[new_account.clone(), system_program.clone()],
+            &[signer_seeds],
+        )?;
+
+        invoke_signed(
+            &system_instruction::assign(new_account.key, program_id),
+            &[new_account.clone(), system_program.clone()],
+            &[signer_seeds],
+        )
+    }
+
+    // Helper function to load an obligation owned by this program
+    fn load_obligation(
+        program_id: &Pubkey,
+        obligation_account: &AccountInfo,
+    ) -> Result<Obligation, ProgramError> {
+        // Verify obligation account
+        if obligation_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let obligation = Obligation::try_from_slice(&obligation_account.data.borrow())?;
+
+        if find_obligation_pda(
+            &obligation.market,
+            &obligation.creator,
+            obligation.index,
+            program_id,
+        )
+        .0 != *obligation_account.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(obligation)
+    }
+
+    // Helper function to load the global config owned by this program
+    fn load_global_config(
+        program_id: &Pubkey,
+        global_config_account: &AccountInfo,
+    ) -> Result<ZFubaoConfig, ProgramError> {
+        let global_config = Self::load_locked_global_config(program_id, global_config_account)?;
+
+        // Nothing runs between a flash loan and its repayment, not even by CPI
+        if global_config.flash_loan_market != Pubkey::default() {
+            return Err(ZFubaoError::FlashLoanInProgress.into());
+        }
+
+        Ok(global_config)
+    }
+
+    // Helper function to load the global config, even one locked by a running
+    // flash loan
+    fn load_locked_global_config(
+        program_id: &Pubkey,
+        global_config_account: &AccountInfo,
+    ) -> Result<ZFubaoConfig, ProgramError> {
+        if global_config_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let global_config = ZFubaoConfig::try_from_slice(&global_config_account.data.borrow())?;
+
+        let global_config_pda = Pubkey::create_program_address(
+            &[GLOBAL_CONFIG_SEED, &[global_config.global_config_bump]],
+            program_id,
+        )?;
+        if global_config_pda != *global_config_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(global_config)
+    }
+
+    // Helper function to load the global config on behalf of its admin
+    fn load_global_config_as_admin(
+        program_id: &Pubkey,
+        global_config_account: &AccountInfo,
+        admin: &AccountInfo,
+    ) -> Result<ZFubaoConfig, ProgramError> {
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let global_config = Self::load_global_config(program_id, global_config_account)?;
+
+        if global_config.admin != *admin.key {
+            return Err(ZFubaoError::Unauthorized.into());
+        }
+
+        Ok(global_config)
+    }
+
+    // Helper function to load the staker account of `owner`, None while it does
+    // not exist yet
+    fn load_staker(
+        program_id: &Pubkey,
+        owner: &Pubkey,
+        staker_account: &AccountInfo,
+    ) -> Result<Option<Staker>, ProgramError> {
+        if *staker_account.key != find_staker_pda(owner, program_id).0 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if staker_account.owner != program_id {
+            return Ok(None);
+        }
+
+        Ok(Some(Staker::try_from_slice(&staker_account.data.borrow())?))
+    }
+
+    // Helper function to create the staker account of `owner`, who pays the rent
+    fn create_staker<'a>(
+        program_id: &Pubkey,
+        owner: &AccountInfo<'a>,
+        staker_account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+    ) -> Result<Staker, ProgramError> {
+        let (_, bump) = find_staker_pda(owner.key, program_id);
+
+        msg!("Create staker account");
+
+        Self::create_pda_account(
+            owner,
+            staker_account,
+            system_program,
+            program_id,
+            Staker::LEN,
+            &[STAKER_SEED, owner.key.as_ref(), &[bump]],
+        )?;
+
+        // Snapshots are taken at the first settlement, before any shares exist
+        Ok(Staker {
+            owner: *owner.key,
+            bump,
+            shares: 0,
+            gains: [CollateralGain::default(); MAX_POOL_MARKETS],
+        })
+    }
+
+    // Helper function to value the SZUSD market's collateral at the current rate
+    fn sync_szusd_market(
+        program_id: &Pubkey,
+        szusd_market_account: &AccountInfo,
+        global_config: &ZFubaoConfig,
+    ) -> ProgramResult {
+        // Write-downs have to reach the one market pricing SZUSD
+        if *szusd_market_account.key != find_market_pda(&global_config.szusd_mint, program_id).0 {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // Before SZUSD is listed there is no price to sync
+        if szusd_market_account.owner != program_id {
+            return Ok(());
+        }
+
+        let mut market = Self::load_market(program_id, szusd_market_account)?;
+        if market.price_source != PriceSource::SzusdExchangeRate {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        // A settled market keeps the price it was settled at
+        if market.settled {
+            return Ok(());
+        }
+
+        market.price = global_config.szusd_price_ratio;
+        market.serialize(&mut &mut szusd_market_account.data.borrow_mut()[..])?;
+
+        Ok(())
+    }
+
+    // Helper function to load a market owned by this program that no flash loan
+    // locks
+    fn load_market(
+        program_id: &Pubkey,
+        market_account: &AccountInfo,
+    ) -> Result<Market, ProgramError> {
+        let market = Self::load_locked_market(program_id, market_account)?;
+
+        // Nothing uses the market between a flash loan and its repayment
+        if market.flash_loan_due != 0 {
+            return Err(ZFubaoError::FlashLoanInProgress.into());
+        }
+
+        Ok(market)
+    }
+
+    // Helper function to load a market owned by this program, even one locked by
+    // a running flash loan
+    fn load_locked_market(
+        program_id: &Pubkey,
+        market_account: &AccountInfo,
+    ) -> Result<Market, ProgramError> {
+        if market_account.owner != program_id {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let market = Market::try_from_slice(&market_account.data.borrow())?;
+
+        let market_pda = Pubkey::create_program_address(
+            &[
+                MARKET_SEED,
+                market.collateral_mint.as_ref(),
+                &[market.market_bump],
+            ],
+            program_id,
+        )?;
+        if market_pda != *market_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(market)
+    }
+
+    // Helper function to load a running auction
+    fn load_auction(
+        program_id: &Pubkey,
+        auction_account: &AccountInfo,
+    ) -> Result<Auction, ProgramError> {
+        if auction_account.owner != program_id {
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        let auction = Auction::try_from_slice(&auction_account.data.borrow())?;
+
+        let auction_pda = Pubkey::create_program_address(
+            &[
+                AUCTION_SEED,
+                auction.obligation.as_ref(),
+                auction.market.as_ref(),
+                &[auction.bump],
+            ],
+            program_id,
+        )?;
+        if auction_pda != *auction_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(auction)
+    }
+
+    // Helper function to load a trigger owned by this program
+    fn load_trigger(
+        program_id: &Pubkey,
+        trigger_account: &AccountInfo,
+    ) -> Result<Trigger, ProgramError> {
+        if trigger_account.owner != program_id {
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        let trigger = Trigger::try_from_slice(&trigger_account.data.borrow())?;
+
+        let trigger_pda = Pubkey::create_program_address(
+            &[TRIGGER_SEED, trigger.obligation.as_ref(), &[trigger.bump]],
+            program_id,
+        )?;
+        if trigger_pda != *trigger_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        Ok(trigger)
+    }
+
+    // Helper function to close the trigger of an obligation, if it has one, and
+    // move its rent lamports to `recipient`
+    fn close_trigger(
+        program_id: &Pubkey,
+        obligation_key: &Pubkey,
+        trigger_account: &AccountInfo,
+        recipient: &AccountInfo,
+    ) -> ProgramResult {
+        if *trigger_account.key != find_trigger_pda(obligation_key, program_id).0 {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if trigger_account.owner != program_id {
+            return Ok(());
+        }
+
+        let recipient_lamports = recipient.lamports();
+        **recipient.lamports.borrow_mut() = recipient_lamports
+            .checked_add(trigger_account.lamports())
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        **trigger_account.lamports.borrow_mut() = 0;
+
+        trigger_account.data.borrow_mut().fill(0);
+        trigger_account.realloc(0, false)?;
+        trigger_account.assign(&system_program::id());
+
+        Ok(())
+    }
+
+    // Helper function to load the market an obligation was opened in
+    fn load_obligation_market(
+        program_id: &Pubkey,
+        market_account: &AccountInfo,
+        obligation: &Obligation,
+    ) -> Result<Market, ProgramError> {
+        if obligation.market != *market_account.key {
+            return Err(ZFubaoError::MarketMismatch.into());
+        }
+
+        Self::load_market(program_id, market_account)
+    }
+
+    // Helper function to collect the markets pricing an obligation's collateral,
+    // starting with the market passed in the fixed accounts. The markets of the
+    // other deposits follow in `deposit_market_accounts`, in deposit order.
+    fn load_deposit_markets(
+        program_id: &Pubkey,
+        obligation: &Obligation,
+        market_account: &AccountInfo,
+        market: &Market,
+        deposit_market_accounts: &[AccountInfo],
+    ) -> Result<Vec<(Pubkey, Market)>, ProgramError> {
+        let mut markets = vec![(*market_account.key, market.clone())];
+        let mut deposit_market_accounts = deposit_market_accounts.iter();
+
+        for deposit in obligation.deposits() {
+            if deposit.market == *market_account.key {
+                continue;
+            }
+
+            let deposit_market_account = deposit_market_accounts
+                .next()
+                .filter(|account| *account.key == deposit.market)
+                .ok_or(ZFubaoError::DepositMarketMismatch)?;
+            markets.push((
+                deposit.market,
+                Self::load_market(program_id, deposit_market_account)?,
+            ));
+        }
+
+        Ok(markets)
+    }
+
+    // Helper function to check a token account is the collateral vault of the market
+    fn check_market_vault(market: &Market, vault_account: &AccountInfo) -> ProgramResult {
+        if *vault_account.key
+            != get_associated_token_address(&market.authority, &market.collateral_mint)
+        {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
+
+        Ok(())
+    }
+
+    // Helper function to check a token account is the insurance fund
+    fn check_insurance_fund(
+        program_id: &Pubkey,
+        zusd_mint: &Pubkey,
+        insurance_fund: &AccountInfo,
+    ) -> ProgramResult {
+        if *insurance_fund.key
+            != get_associated_token_address(&find_insurance_pda(program_id).0, zusd_mint)
+        {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
+
+        Ok(())
+    }
+
+    // Helper function to check a token account is the vault of a configured PSM
+    fn check_psm_vault(global_config: &ZFubaoConfig, vault_account: &AccountInfo) -> ProgramResult {
+        if global_config.psm_mint == Pubkey::default() {
+            return Err(ZFubaoError::InvalidMint.into());
+        }
+
+        if *vault_account.key
+            != get_associated_token_address(&global_config.authority, &global_config.psm_mint)
+        {
+            return Err(ZFubaoError::InvalidVault.into());
+        }
+
+        Ok(())
+    }
+
+    // Helper function to find a FlashMintRepay of `due` ZUSD after the running
+    // instruction. The sysvar only lists top-level instructions, so the running
+    // one has to be top-level too rather than a CPI.
+    fn check_flash_mint_repaid(
+        program_id: &Pubkey,
+        instructions_sysvar: &AccountInfo,
+        due: u64,
+    ) -> ProgramResult {
+        if get_stack_height() > TRANSACTION_LEVEL_STACK_HEIGHT {
+            return Err(ZFubaoError::FlashMintNotRepaid.into());
+        }
+
+        let current_index = load_current_index_checked(instructions_sysvar)? as usize;
+
+        let repay_data = ZFubaoInstruction::FlashMintRepay { amount: due }.pack();
+        let mut index = current_index + 1;
+        while let Ok(instruction) = load_instruction_at_checked(index, instructions_sysvar) {
+            if instruction.program_id == *program_id && instruction.data == repay_data {
+                return Ok(());
+            }
+            index += 1;
+        }
+
+        Err(ZFubaoError::FlashMintNotRepaid.into())
+    }
+
+    // Helper function to check that the next instruction of this program after
+    // the running one is the FlashRepayZbtc of `due` collateral to `market`. Like
+    // for flash mints the running instruction has to be top-level.
+    fn check_flash_loan_repaid(
+        program_id: &Pubkey,
+        instructions_sysvar: &AccountInfo,
+        market: &Pubkey,
+        due: u64,
+    ) -> ProgramResult {
+        if get_stack_height() > TRANSACTION_LEVEL_STACK_HEIGHT {
+            return Err(ZFubaoError::FlashLoanNotRepaid.into());
+        }
+
+        let current_index = load_current_index_checked(instructions_sysvar)? as usize;
+
+        let repay_data = ZFubaoInstruction::FlashRepayZbtc { amount: due }.pack();
+        let mut index = current_index + 1;
+        while let Ok(instruction) = load_instruction_at_checked(index, instructions_sysvar) {
+            if instruction.program_id == *program_id {
+                // Nothing else of this program runs before the repayment
+                let repays_market = instruction
+                    .accounts
+                    .get(1)
+                    .is_some_and(|account| account.pubkey == *market);
+                if instruction.data == repay_data && repays_market {
+                    return Ok(());
+                }
+                break;
+            }
+            index += 1;
+        }
+
+        Err(ZFubaoError::FlashLoanNotRepaid.into())
+    }
+
+    // Helper function to load an SPL mint
+    fn load_mint(mint: &AccountInfo) -> Result<spl_token::state::Mint, ProgramError> {
+        if *mint.owner != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        spl_token::state::Mint::unpack(&mint.data.borrow())
+    }
+
+    // Helper function to check who owns an SPL token account
+    fn check_token_account_owner(token_account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+        if *token_account.owner != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let token_account_data = spl_token::state::Account::unpack(&token_account.data.borrow())?;
+        if token_account_data.owner != *owner {
+            return Err(ProgramError::IllegalOwner);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::AUCTION_DURATION;
+
+    // The ZBTC market the sample obligations deposit into
+    const MARKET: Pubkey = Pubkey::new_from_array([1; 32]);
+
+    // Config with every field zeroed, tests set the ones they read
+    fn zeroed_config() -> ZFubaoConfig {
+        ZFubaoConfig::try_from_slice(&vec![0; ZFubaoConfig::LEN]).unwrap()
+    }
+
+    // Market matching setup_protocol: 75% LTV, ZBTC at $50,000, no caps
+    fn sample_market() -> Market {
+        Market {
+            authority: Pubkey::new_unique(),
+            collateral_mint: Pubkey::new_unique(),
+            zusd_mint: Pubkey::new_unique(),
+            collateral_decimals: 9,
+            zusd_decimals: 6,
+            authority_bump: 0,
+            market_bump: 0,
+            ltv_ratio: 7_500,
+            price_source: PriceSource::Admin,
+            price: 50_000,
+            deposit_cap: u64::MAX,
+            borrow_cap: u64::MAX,
+            total_deposits: 0,
+            total_borrowed: 0,
+            settled: false,
+            settled_collateral: 0,
+            flash_loan_due: 0,
+            redemption_price: 50_000,
+            redemption_buckets: [0; REDEMPTION_BUCKETS],
+        }
+    }
+
+    fn sample_obligation(zbtc_deposit: u64, zusd_borrowed: u64) -> Obligation {
+        let mut obligation = Obligation {
+            owner: Pubkey::new_unique(),
+            creator: Pubkey::new_unique(),
+            index: 0,
+            market: MARKET,
+            delegate: Pubkey::default(),
+            delegate_permission: DelegatePermission::None,
+            deposits: [CollateralDeposit::default(); MAX_DEPOSITS],
+            zusd_borrowed,
+            auctions: 0,
+            redemption_bucket: NO_REDEMPTION_BUCKET,
+        };
+        obligation.add_deposit(&MARKET, zbtc_deposit).unwrap();
+        obligation
+    }
+
+    // Markets pricing an obligation that only holds ZBTC
+    fn zbtc_only(market: &Market) -> [(Pubkey, Market); 1] {
+        [(MARKET, market.clone())]
+    }
+
+    #[test]
+    fn test_szusd_price_accrual() {
+        // Testing Scenario:
+        // 1. The exchange rate grows one bps per accrual period
+        // 2. Refreshing again at the same time does not compound the rate
+        // 3. Partial periods carry over to the next refresh
+        let mut global_config = ZFubaoConfig {
+            szusd_mint: Pubkey::new_unique(),
+            szusd_price_ratio: 10_000,
+            ..zeroed_config()
+        };
+
+        global_config.accrue_szusd_price(2_500).unwrap();
+        assert_eq!(global_config.szusd_price_ratio, 10_002);
+        assert_eq!(global_config.start_time, 2_000);
+
+        global_config.accrue_szusd_price(2_500).unwrap();
+        assert_eq!(global_config.szusd_price_ratio, 10_002);
+
+        global_config.accrue_szusd_price(3_000).unwrap();
+        assert_eq!(global_config.szusd_price_ratio, 10_003);
+        assert_eq!(global_config.start_time, 3_000);
+
+        // A clock behind the checkpoint leaves the rate alone
+        global_config.accrue_szusd_price(0).unwrap();
+        assert_eq!(global_config.szusd_price_ratio, 10_003);
+
+        // 1 szUSD at 1.0003 ZUSD
+        let market = Market {
+            collateral_mint: global_config.szusd_mint,
+            price_source: PriceSource::SzusdExchangeRate,
+            price: global_config.szusd_price_ratio,
+            ..sample_market()
+        };
+        assert_eq!(market.collateral_price(), Decimal::from_bps(10_003));
+    }
+
+    #[test]
+    fn test_mint_decimals() {
+        // Testing Scenario:
+        // 1. Borrow and withdraw limits round in the protocol's favour
+        // 2. Limits follow the mint decimals recorded at Initialize
+        let mut market = sample_market();
+        let mut obligation = sample_obligation(1_000_000_000, 0); // 1 ZBTC
+
+        // 1 ZBTC at $50,000 with 75% LTV backs 37,500 ZUSD
+        assert_eq!(
+            Processor::calculate_borrow_limit(&obligation, &zbtc_only(&market)),
+            Ok(37_500_000_000)
+        );
+        assert_eq!(
+            Processor::calculate_position_status(&obligation, &zbtc_only(&market))
+                .map(|position| position.max_withdrawable),
+            Ok(1_000_000_000)
+        );
+
+        // The smallest debt still locks the smallest unit of collateral
+        obligation.zusd_borrowed = 1;
+        assert_eq!(
+            Processor::calculate_position_status(&obligation, &zbtc_only(&market))
+                .map(|position| position.max_withdrawable),
+            Ok(999_999_999)
+        );
+
+        // A position at the limit has nothing left to withdraw
+        obligation.zusd_borrowed = 37_500_000_000;
+        assert_eq!(
+            Processor::calculate_position_status(&obligation, &zbtc_only(&market))
+                .map(|position| position.max_withdrawable),
+            Ok(0)
+        );
+
+        // The same position with 8-decimal ZBTC and 9-decimal ZUSD
+        market.collateral_decimals = 8;
+        market.zusd_decimals = 9;
+        let obligation = sample_obligation(100_000_000, 37_500_000_000_000); // 1 ZBTC, 37,500 ZUSD
+        assert_eq!(
+            Processor::calculate_borrow_limit(&obligation, &zbtc_only(&market)),
+            Ok(37_500_000_000_000)
+        );
+        assert_eq!(
+            Processor::calculate_position_status(&obligation, &zbtc_only(&market))
+                .map(|position| position.max_withdrawable),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn test_position_status() {
+        // Testing Scenario:
+        // 1. Headroom shrinks to zero exactly at the LTV limit
+        // 2. One unit past the limit, or a price drop, reports undercollateralized
+        //    with zero headroom instead of underflowing
+        // 3. Degenerate configs and positions do not error
+        let market = sample_market();
+        let one_zbtc: u64 = 1_000_000_000;
+        let limit: u64 = 37_500_000_000; // 37,500 ZUSD for 1 ZBTC
+
+        let status = |obligation: &Obligation, market: &Market| {
+            Processor::calculate_position_status(obligation, &zbtc_only(market)).unwrap()
+        };
+
+        // ==================================================================================
+        // Test Case 1: No debt
+        // ==================================================================================
+        assert_eq!(
+            status(&sample_obligation(one_zbtc, 0), &market),
+            PositionStatus {
+                state: CollateralState::Healthy,
+                borrow_limit: limit,
+                max_borrowable: limit,
+                max_withdrawable: one_zbtc,
+            }
+        );
+
+        // ==================================================================================
+        // Test Case 2: One unit below and exactly at the limit
+        // ==================================================================================
+        let below_limit = status(&sample_obligation(one_zbtc, limit - 1), &market);
+        assert_eq!(below_limit.state, CollateralState::Healthy);
+        assert_eq!(below_limit.max_borrowable, 1);
+        assert_eq!(below_limit.max_withdrawable, 0); // 1e-6 ZUSD frees less than 1e-9 ZBTC
+
+        assert_eq!(
+            status(&sample_obligation(one_zbtc, limit), &market),
+            PositionStatus {
+                state: CollateralState::Healthy,
+                borrow_limit: limit,
+                max_borrowable: 0,
+                max_withdrawable: 0,
+            }
+        );
+
+        // ==================================================================================
+        // Test Case 3: One unit past the limit
+        // ==================================================================================
+        let past_limit = status(&sample_obligation(one_zbtc, limit + 1), &market);
+        assert!(past_limit.is_undercollateralized());
+        assert_eq!(past_limit.max_borrowable, 0);
+        assert_eq!(past_limit.max_withdrawable, 0);
+
+        // ==================================================================================
+        // Test Case 4: Price drop below the liquidation price
+        // ==================================================================================
+        let mut crashed_market = sample_market();
+        crashed_market.price = 20_000;
+        let crashed = status(
+            &sample_obligation(one_zbtc, 30_000_000_000),
+            &crashed_market,
+        );
+        assert_eq!(
+            crashed,
+            PositionStatus {
+                state: CollateralState::Undercollateralized,
+                borrow_limit: 15_000_000_000,
+                max_borrowable: 0,
+                max_withdrawable: 0,
+            }
+        );
+
+        crashed_market.price = 0;
+        assert!(status(&sample_obligation(one_zbtc, 1), &crashed_market).is_undercollateralized());
+
+        // ==================================================================================
+        // Test Case 5: Degenerate positions
+        // ==================================================================================
+        assert_eq!(
+            status(&sample_obligation(0, 0), &market),
+            PositionStatus {
+                state: CollateralState::Healthy,
+                borrow_limit: 0,
+                max_borrowable: 0,
+                max_withdrawable: 0,
+            }
+        );
+        assert!(status(&sample_obligation(0, 1), &market).is_undercollateralized());
+
+        let mut zero_ltv_market = sample_market();
+        zero_ltv_market.ltv_ratio = 0;
+        let zero_ltv = status(&sample_obligation(one_zbtc, 0), &zero_ltv_market);
+        assert_eq!(zero_ltv.max_borrowable, 0);
+        assert_eq!(zero_ltv.max_withdrawable, one_zbtc);
+    }
+
+    #[test]
+    fn test_redemption_limits() {
+        // Testing Scenario:
+        // 1. A redemption takes no more than the obligation's debt
+        // 2. Debt above the collateral value only redeems what the collateral is worth
+        // 3. The fee rounds up, in the obligation's favour
+        // 4. The insurance fund's share of the fee is paid in ZUSD on top of the debt
+        let market = sample_market();
+
+        // 1 ZBTC at $50,000 owing 10,000 ZUSD
+        let obligation = sample_obligation(1_000_000_000, 10_000_000_000);
+        assert_eq!(
+            Processor::calculate_redemption(&obligation, &MARKET, &market, u64::MAX),
+            Ok(Redemption {
+                zusd_amount: 10_000_000_000,
+                insurance_fee: 25_000_000,
+                collateral_amount: 199_500_000,
+                fee: 500_000,
+            })
+        );
+
+        // Bad debt: 0.1 ZBTC owing 10,000 ZUSD
+        let obligation = sample_obligation(100_000_000, 10_000_000_000);
+        assert_eq!(
+            Processor::calculate_redemption(&obligation, &MARKET, &market, u64::MAX),
+            Ok(Redemption {
+                zusd_amount: 5_000_000_000,
+                insurance_fee: 12_500_000,
+                collateral_amount: 99_750_000,
+                fee: 250_000,
+            })
+        );
+
+        // 1.005 ZUSD of debt buys 20,100 raw ZBTC, a fee of 100.5 rounds up to 101.
+        // The 0.002512 ZUSD paid on top buy 50 of it for the insurance fund.
+        assert_eq!(
+            Processor::calculate_redemption(&obligation, &MARKET, &market, 1_007_513),
+            Ok(Redemption {
+                zusd_amount: 1_005_000,
+                insurance_fee: 2_512,
+                collateral_amount: 20_049,
+                fee: 51,
+            })
+        );
+    }
+
+    #[test]
+    fn test_redemption_buckets() {
+        // Testing Scenario:
+        // 1. A 100% collateral ratio lands in the middle bucket, 200% eight buckets above
+        // 2. Ratios past either end are clamped into the first and last bucket
+        // 3. Positions without debt or collateral are in no bucket
+        let market = sample_market();
+
+        // 1 ZBTC at the $50,000 opening price
+        assert_eq!(
+            market.redemption_bucket(1_000_000_000, 50_000_000_000),
+            Ok(32)
+        );
+        assert_eq!(
+            market.redemption_bucket(1_000_000_000, 25_000_000_000),
+            Ok(40)
+        );
+        assert_eq!(
+            market.redemption_bucket(1_000_000_000, 49_000_000_000),
+            Ok(32)
+        );
+        assert_eq!(
+            market.redemption_bucket(1_000_000_000, 51_000_000_000),
+            Ok(31)
+        );
+
+        assert_eq!(market.redemption_bucket(1, 50_000_000_000), Ok(0));
+        assert_eq!(market.redemption_bucket(1_000_000_000, 1), Ok(63));
+
+        assert_eq!(
+            market.redemption_bucket(0, 50_000_000_000),
+            Ok(NO_REDEMPTION_BUCKET)
+        );
+        assert_eq!(
+            market.redemption_bucket(1_000_000_000, 0),
+            Ok(NO_REDEMPTION_BUCKET)
+        );
+    }
+
+    #[test]
+    fn test_stability_pool_accounting() {
+        // Testing Scenario:
+        // 1. Liquidations never take the szUSD price to zero
+        // 2. Liquidations take no more than the debt, the pool or the collateral allow
+        // 3. Gains are spread over the recorded shares and settle once
+        let market = sample_market();
+        let mut global_config = ZFubaoConfig {
+            szusd_price_ratio: 10_000,
+            total_staked_shares: 4_000,
+            stability_pool: [PoolMarket::default(); MAX_POOL_MARKETS],
+            ..zeroed_config()
+        };
+
+        // ==================================================================================
+        // Test Case 1: The szUSD price
+        // ==================================================================================
+        assert_eq!(global_config.stability_pool_capacity(10_000), 9_999);
+        global_config.write_down_szusd_price(10_000, 9_999).unwrap();
+        assert_eq!(global_config.szusd_price_ratio, 1);
+        assert_eq!(global_config.stability_pool_capacity(10_000), 0);
+
+        // ==================================================================================
+        // Test Case 2: Liquidation limits
+        // ==================================================================================
+        // 1 ZBTC at $50,000 owing 40,000 ZUSD
+        let obligation = sample_obligation(1_000_000_000, 40_000_000_000);
+        assert_eq!(
+            Processor::calculate_liquidation(&obligation, &MARKET, &market, &market, u64::MAX),
+            Ok(Liquidation {
+                zusd_amount: 40_000_000_000,
+                collateral_amount: 840_000_000,
+            })
+        );
+        assert_eq!(
+            Processor::calculate_liquidation(
+                &obligation,
+                &MARKET,
+                &market,
+                &market,
+                10_000_000_000
+            ),
+            Ok(Liquidation {
+                zusd_amount: 10_000_000_000,
+                collateral_amount: 210_000_000,
+            })
+        );
+
+        // Bad debt: the collateral, bonus included, covers 47,619.047619 ZUSD
+        let obligation = sample_obligation(1_000_000_000, 60_000_000_000);
+        assert_eq!(
+            Processor::calculate_liquidation(&obligation, &MARKET, &market, &market, u64::MAX),
+            Ok(Liquidation {
+                zusd_amount: 47_619_047_619,
+                collateral_amount: 999_999_999,
+            })
+        );
+
+        // ==================================================================================
+        // Test Case 3: Gains per share
+        // ==================================================================================
+        let mut staker = Staker {
+            shares: 3_000,
+            ..Staker::try_from_slice(&vec![0; Staker::LEN]).unwrap()
+        };
+        global_config.add_pool_gains(&MARKET, 1_000).unwrap();
+        staker.settle_gains(&global_config).unwrap();
+        staker.settle_gains(&global_config).unwrap();
+        assert_eq!(global_config.stability_pool[0].market, MARKET);
+        assert_eq!(staker.gains[0].pending, 750);
+
+        // Every slot taken by other markets
+        for _ in 1..MAX_POOL_MARKETS {
+            global_config
+                .add_pool_gains(&Pubkey::new_unique(), 1)
+                .unwrap();
+        }
+        assert_eq!(
+            global_config.add_pool_gains(&Pubkey::new_unique(), 1),
+            Err(ZFubaoError::StabilityPoolFull.into())
+        );
+        global_config.add_pool_gains(&MARKET, 1_000).unwrap();
+        staker.settle_gains(&global_config).unwrap();
+        assert_eq!(staker.gains[0].pending, 1_500);
+    }
+
+    #[test]
+    fn test_deleverage_limits() {
+        // Testing Scenario:
+        // 1. The pool pays the oracle price less 1% for the collateral
+        // 2. The debt or the ZUSD in the pool cap the collateral sold
+        // 3. The sold collateral rounds up, in the stakers' favour
+        let market = sample_market();
+
+        // 1 ZBTC at $50,000 owing 10,000 ZUSD
+        let obligation = sample_obligation(1_000_000_000, 10_000_000_000);
+        assert_eq!(
+            Processor::calculate_deleverage(
+                &obligation,
+                &MARKET,
+                &market,
+                &market,
+                100_000_000,
+                u64::MAX
+            ),
+            Ok(Deleveraging {
+                zusd_amount: 4_950_000_000,
+                collateral_amount: 100_000_000,
+                fee: 1_000_000,
+            })
+        );
+
+        // 10,000 ZUSD at $49,500 takes 0.2020202... ZBTC
+        assert_eq!(
+            Processor::calculate_deleverage(
+                &obligation,
+                &MARKET,
+                &market,
+                &market,
+                u64::MAX,
+                u64::MAX
+            ),
+            Ok(Deleveraging {
+                zusd_amount: 10_000_000_000,
+                collateral_amount: 202_020_203,
+                fee: 2_020_203,
+            })
+        );
+
+        // A pool of 1,000 ZUSD buys 0.0202020... ZBTC
+        assert_eq!(
+            Processor::calculate_deleverage(
+                &obligation,
+                &MARKET,
+                &market,
+                &market,
+                u64::MAX,
+                1_000_000_000
+            ),
+            Ok(Deleveraging {
+                zusd_amount: 1_000_000_000,
+                collateral_amount: 20_202_021,
+                fee: 202_021,
+            })
+        );
+    }
+
+    #[test]
+    fn test_trigger_deleverage() {
+        // Testing Scenario:
+        // 1. A trigger sells just enough collateral to restore the target health
+        // 2. The debt caps the sale when the target cannot be reached
+        // 3. A sale the pool cuts short earns only its share of the keeper tip
+        let market = sample_market();
+        let markets = [(MARKET, market.clone())];
+
+        // 1 ZBTC at $50,000 owing 36,000 ZUSD, health 1.0417. Back at 1.25 it
+        // takes 7,500 / (1.25 * 0.99 - 0.75) = $15,384.62 of collateral.
+        let obligation = sample_obligation(1_000_000_000, 36_000_000_000);
+        let deleveraging = Processor::calculate_trigger_deleverage(
+            &obligation,
+            &markets,
+            &MARKET,
+            12_500,
+            u64::MAX,
+        )
+        .unwrap();
+        assert_eq!(
+            deleveraging,
+            Deleveraging {
+                zusd_amount: 15_230_769_246,
+                collateral_amount: 307_692_308,
+                fee: 3_076_923,
+            }
+        );
+
+        let mut obligation = obligation;
+        obligation.zusd_borrowed -= deleveraging.zusd_amount;
+        obligation
+            .remove_deposit(&MARKET, deleveraging.collateral_amount)
+            .unwrap();
+        let health = Processor::calculate_obligation_health(&obligation, &markets)
+            .unwrap()
+            .health_factor;
+        assert!(health >= 1_250_000_000_000_000_000);
+        assert!(health < 1_250_000_100_000_000_000);
+
+        // At a 100% LTV no sale restores health, so it clears the debt
+        let full_ltv_market = Market {
+            ltv_ratio: 10_000,
+            ..sample_market()
+        };
+        let obligation = sample_obligation(1_000_000_000, 49_000_000_000);
+        assert_eq!(
+            Processor::calculate_trigger_deleverage(
+                &obligation,
+                &[(MARKET, full_ltv_market)],
+                &MARKET,
+                10_100,
+                u64::MAX,
+            )
+            .unwrap()
+            .zusd_amount,
+            49_000_000_000
+        );
+
+        // A pool holding a third of the sale pays a third of the tip, and ten
+        // dust executions of a tenth each pay no more than one full one
+        assert_eq!(
+            Processor::calculate_keeper_tip(
+                1_000_000,
+                deleveraging.zusd_amount / 3,
+                deleveraging.zusd_amount,
+            )
+            .unwrap(),
+            333_333
+        );
+        assert!(
+            10 * Processor::calculate_keeper_tip(
+                1_000_000,
+                deleveraging.zusd_amount / 10,
+                deleveraging.zusd_amount,
+            )
+            .unwrap()
+                <= 1_000_000
+        );
+        assert_eq!(
+            Processor::calculate_keeper_tip(
+                1_000_000,
+                deleveraging.zusd_amount,
+                deleveraging.zusd_amount
+            )
+            .unwrap(),
+            1_000_000
+        );
+    }
+
+    #[test]
+    fn test_auction_fill() {
+        // Testing Scenario:
+        // 1. The price falls linearly from the start price to the floor and stays there
+        // 2. A bid pays no more than the debt plus penalty or the collateral left is worth
+        let market = sample_market();
+        let one_usd = 10u128.pow(18);
+        let auction = Auction {
+            obligation: Pubkey::new_unique(),
+            market: MARKET,
+            owner: Pubkey::new_unique(),
+            bump: 255,
+            start_time: 1_000,
+            start_price: 60_000 * one_usd,
+            floor_price: 25_000 * one_usd,
+            collateral_amount: 1_000_000_000,
+        };
+
+        // ==================================================================================
+        // Test Case 1: Price decay
+        // ==================================================================================
+        for (now, price) in [
+            (0, 60_000),
+            (1_000, 60_000),
+            (1_000 + AUCTION_DURATION / 2, 42_500),
+            (1_000 + AUCTION_DURATION, 25_000),
+            (1_000 + 10 * AUCTION_DURATION, 25_000),
+        ] {
+            assert_eq!(
+                auction.current_price(now).unwrap(),
+                Decimal::from_u64(price),
+                "price at {}",
+                now
+            );
+        }
+
+        // ==================================================================================
+        // Test Case 2: Fills
+        // ==================================================================================
+        let now = 1_000 + AUCTION_DURATION;
+
+        // 10,300 ZUSD buys 0.412 ZBTC at $25,000 and repays 10,000 ZUSD
+        assert_eq!(
+            Processor::calculate_auction_fill(
+                &auction,
+                &market,
+                30_000_000_000,
+                10_300_000_000,
+                now
+            ),
+            Ok(AuctionFill {
+                zusd_amount: 10_300_000_000,
+                collateral_amount: 412_000_000,
+                penalty: 300_000_000,
+            })
+        );
+        // No more than the debt and its penalty
+        assert_eq!(
+            Processor::calculate_auction_fill(&auction, &market, 5_000_000_000, u64::MAX, now),
+            Ok(AuctionFill {
+                zusd_amount: 5_150_000_000,
+                collateral_amount: 206_000_000,
+                penalty: 150_000_000,
+            })
+        );
+        // No more than the collateral is worth, which is sold out
+        assert_eq!(
+            Processor::calculate_auction_fill(&auction, &market, 30_000_000_000, u64::MAX, now),
+            Ok(AuctionFill {
+                zusd_amount: 25_000_000_000,
+                collateral_amount: 1_000_000_000,
+                penalty: 728_155_339,
+            })
+        );
+        // Partial fills round the collateral and the penalty down, 1.000001 ZUSD
+        // buys 40,000.04 raw ZBTC and repays 970,874.76 raw ZUSD
+        assert_eq!(
+            Processor::calculate_auction_fill(&auction, &market, 30_000_000_000, 1_000_001, now),
+            Ok(AuctionFill {
+                zusd_amount: 1_000_001,
+                collateral_amount: 40_000,
+                penalty: 29_126,
+            })
+        );
+    }
+
+    #[test]
+    fn test_settlement_redemption() {
+        // Testing Scenario:
+        // 1. ZUSD redeems at the settlement rate, at most $1
+        // 2. Redemptions stop at the settled debt and the market's settled collateral
+        let mut market = sample_market();
+        market.price = 30_000;
+        market.settled = true;
+        market.settled_collateral = 1_300_000_000;
+        let mut global_config = ZFubaoConfig {
+            zusd_decimals: 6,
+            settled_at: 1,
+            settlement_debt: 42_000_000_000,
+            settlement_value: Decimal::from_u64(39_000).to_scaled_val().unwrap(),
+            ..zeroed_config()
+        };
+
+        // 24,000 ZUSD get $22,285.71 in ZBTC at $30,000
+        let redemption =
+            Processor::calculate_settlement_redemption(&global_config, &market, 24_000_000_000)
+                .unwrap();
+        assert_eq!(redemption.zusd_amount, 24_000_000_000);
+        assert_eq!(redemption.collateral_amount, 742_857_142);
+
+        // No more than the settled debt
+        let redemption =
+            Processor::calculate_settlement_redemption(&global_config, &market, u64::MAX).unwrap();
+        assert_eq!(redemption.zusd_amount, 42_000_000_000);
+        assert_eq!(redemption.collateral_amount, 1_299_999_999);
+
+        // No more than the market holds, 0.1 ZBTC pays for 3,230.77 ZUSD rounded up
+        market.settled_collateral = 100_000_000;
+        let redemption =
+            Processor::calculate_settlement_redemption(&global_config, &market, u64::MAX).unwrap();
+        assert_eq!(redemption.zusd_amount, 3_230_769_231);
+        assert_eq!(redemption.collateral_amount, 100_000_000);
+        assert_eq!(redemption.value, Decimal::from_u64(3_000));
+
+        // A surplus pays no more than $1
+        global_config.settlement_value = Decimal::from_u64(50_000).to_scaled_val().unwrap();
+        let redemption =
+            Processor::calculate_settlement_redemption(&global_config, &market, 3_000_000_000)
+                .unwrap();
+        assert_eq!(redemption.collateral_amount, 100_000_000);
+        assert_eq!(redemption.value, Decimal::from_u64(3_000));
+    }
+
+    #[test]
+    fn test_psm_swap() {
+        // Testing Scenario:
+        // 1. Swaps convert between the reserve and ZUSD decimals, rounding down
+        // 2. Fees are taken in ZUSD and round up, in the PSM's favour
+        let global_config = ZFubaoConfig {
+            zusd_decimals: 6,
+            psm_decimals: 8,
+            psm_fee_in_bps: 10,
+            psm_fee_out_bps: 20,
+            ..zeroed_config()
+        };
+
+        // 1.00000001 of the reserve is 1 ZUSD, less 0.1%
+        assert_eq!(
+            Processor::calculate_psm_swap_in(&global_config, 100_000_001),
+            Ok(PsmSwap {
+                amount_out: 999_000,
+                fee: 1_000,
+            })
+        );
+        // 1 ZUSD is 1.00000000 of the reserve, less 0.2%
+        assert_eq!(
+            Processor::calculate_psm_swap_out(&global_config, 1_000_000),
+            Ok(PsmSwap {
+                amount_out: 99_800_000,
+                fee: 2_000,
+            })
+        );
+        // Dust pays a whole unit of fee
+        assert_eq!(
+            Processor::calculate_psm_swap_in(&global_config, 100),
+            Ok(PsmSwap {
+                amount_out: 0,
+                fee: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_obligation_deposits() {
+        // Testing Scenario:
+        // 1. Deposits fill at most MAX_DEPOSITS slots and stay in deposit order
+        // 2. The borrow limit is the LTV-weighted sum of every deposit
+        // 3. Withdrawable collateral accounts for what the other deposits back
+        let mut obligation = sample_obligation(0, 0);
+        let markets: Vec<Pubkey> = (0..=MAX_DEPOSITS).map(|_| Pubkey::new_unique()).collect();
+
+        // ==================================================================================
+        // Test Case 1: Slots
+        // ==================================================================================
+        for (amount, market) in (1..).zip(&markets[..MAX_DEPOSITS]) {
+            obligation.add_deposit(market, amount).unwrap();
+        }
+        assert_eq!(
+            obligation.add_deposit(&markets[MAX_DEPOSITS], 1),
+            Err(ZFubaoError::TooManyDeposits.into())
+        );
+
+        // Emptying a deposit frees its slot and keeps the others in order
+        obligation.add_deposit(&markets[1], 8).unwrap();
+        assert_eq!(obligation.deposited(&markets[1]), 10);
+        obligation.remove_deposit(&markets[1], 10).unwrap();
+        obligation.add_deposit(&markets[MAX_DEPOSITS], 5).unwrap();
+        assert_eq!(
+            obligation
+                .deposits()
+                .map(|deposit| (deposit.market, deposit.amount))
+                .collect::<Vec<_>>(),
+            vec![
+                (markets[0], 1),
+                (markets[2], 3),
+                (markets[3], 4),
+                (markets[MAX_DEPOSITS], 5),
+            ]
+        );
+
+        assert_eq!(
+            obligation.remove_deposit(&markets[0], 2),
+            Err(ZFubaoError::WithdrawLimitExceeded.into())
+        );
+        assert_eq!(
+            obligation.remove_deposit(&markets[1], 1),
+            Err(ZFubaoError::WithdrawLimitExceeded.into())
+        );
+
+        // ==================================================================================
+        // Test Case 2: Weighted borrow limit
+        // ==================================================================================
+        // 1 ZBTC at $50,000 with 75% LTV and 1 WBTC at $40,000 with 50% LTV
+        let wbtc_market_key = Pubkey::new_unique();
+        let mut wbtc_market = sample_market();
+        wbtc_market.collateral_decimals = 8;
+        wbtc_market.ltv_ratio = 5_000;
+        wbtc_market.price = 40_000;
+
+        let mut obligation = sample_obligation(1_000_000_000, 40_000_000_000); // 40,000 ZUSD
+        obligation
+            .add_deposit(&wbtc_market_key, 100_000_000)
+            .unwrap();
+
+        let zbtc_view = [
+            (MARKET, sample_market()),
+            (wbtc_market_key, wbtc_market.clone()),
+        ];
+        let wbtc_view = [
+            (wbtc_market_key, wbtc_market.clone()),
+            (MARKET, sample_market()),
+        ];
+
+        // 37,500 + 20,000 ZUSD
+        assert_eq!(
+            Processor::calculate_borrow_limit(&obligation, &zbtc_view),
+            Ok(57_500_000_000)
+        );
+        assert_eq!(
+            Processor::calculate_position_status(&obligation, &zbtc_view),
+            Ok(PositionStatus {
+                state: CollateralState::Healthy,
+                borrow_limit: 57_500_000_000,
+                max_borrowable: 17_500_000_000,
+                max_withdrawable: 466_666_666, // WBTC backs 20,000, ZBTC the other 20,000
+            })
+        );
+        assert_eq!(
+            Processor::calculate_position_status(&obligation, &wbtc_view)
+                .map(|position| position.max_withdrawable),
+            Ok(87_500_000) // ZBTC backs 37,500, 0.125 WBTC the other 2,500
+        );
+
+        // Every deposit has to be priced
+        assert_eq!(
+            Processor::calculate_position_status(&obligation, &zbtc_only(&sample_market())),
+            Err(ZFubaoError::DepositMarketMismatch.into())
+        );
     }
 }
@@ -1,63 +1,815 @@
-use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::pubkey::Pubkey;
+use borsh::{BorshDeserialize, BorshSchema, BorshSerialize};
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
+
+use crate::{
+    error::ZFubaoError,
+    math::{BPS_SCALER, Decimal, Rounding},
+};
 
 pub const AUTHORITY_SEED: &[u8] = b"authority";
 
 pub const GLOBAL_CONFIG_SEED: &[u8] = b"global_config";
+pub const MARKET_SEED: &[u8] = b"market";
 pub const OBLIGATION_SEED: &[u8] = b"obligation";
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub const STAKER_SEED: &[u8] = b"staker";
+pub const AUCTION_SEED: &[u8] = b"auction";
+pub const INSURANCE_SEED: &[u8] = b"insurance";
+pub const TRIGGER_SEED: &[u8] = b"trigger";
+
+// Markets one obligation can hold collateral in at the same time
+pub const MAX_DEPOSITS: usize = 4;
+
+// Borrowed ZUSD can be staked and deposited again, so a szUSD position can loop
+// up to 1 / (1 - LTV) times its deposit. 90% keeps that at 10x.
+pub const MAX_SZUSD_LTV_RATIO: u16 = 9_000;
+
+// Seconds per basis point of szUSD price growth
+pub const SZUSD_ACCRUAL_PERIOD: i64 = 1_000;
+
+// Share of redeemed collateral left in the redeemed obligation, 0.5%. It pays
+// borrowers for being deleveraged and keeps redeeming below $1 unprofitable.
+pub const REDEMPTION_FEE_BPS: u64 = 50;
+
+// Redemptions sort a market's obligations into buckets by their collateral
+// ratio in that market, priced at the market's opening price so the buckets
+// do not move with the oracle. Each bucket is about 9% wide, 100% sits at
+// bucket 32, and ratios beyond the ends share the first or the last bucket.
+pub const REDEMPTION_BUCKETS: usize = 64;
+// Bucket of obligations without debt or without collateral in their market
+pub const NO_REDEMPTION_BUCKET: u8 = u8::MAX;
+
+// Collateral markets whose liquidations the stability pool can absorb
+pub const MAX_POOL_MARKETS: usize = 8;
+
+// Collateral the stability pool seizes on top of the debt it cancels, 5%
+pub const LIQUIDATION_BONUS_BPS: u64 = 500;
+
+// Discount on collateral the stability pool buys from its owner in a deleverage,
+// 1%. It stays below the liquidation bonus, so deleveraging is the cheaper exit.
+pub const DELEVERAGE_FEE_BPS: u64 = 100;
+
+// Auctions open at 120% of the oracle price and fall linearly to 50% of it over
+// AUCTION_DURATION seconds, where they stay until the collateral is sold
+pub const AUCTION_START_PRICE_BPS: u64 = 12_000;
+pub const AUCTION_FLOOR_PRICE_BPS: u64 = 5_000;
+pub const AUCTION_DURATION: i64 = 3_600;
+
+// ZUSD a flash mint burns on top of the principal, 0.09%
+pub const FLASH_MINT_FEE_BPS: u64 = 9;
+
+// Collateral a flash loan returns on top of the principal, 0.09%, earned by stakers
+pub const FLASH_LOAN_FEE_BPS: u64 = 9;
+
+// ZUSD auctions raise on top of the debt they cover, 3%, paid into the
+// insurance fund that writes off bad debt before stakers have to
+pub const AUCTION_PENALTY_BPS: u64 = 300;
+
+// Share of the redemption and flash mint fees paid into the insurance fund, 50%
+pub const INSURANCE_FEE_SHARE_BPS: u64 = 5_000;
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug)]
 pub struct ZFubaoConfig {
     // general
     //// account
+    pub admin: Pubkey, // creates and tunes markets
     pub authority: Pubkey,
 
     //// mint
-    pub zbtc_mint: Pubkey,
     pub zusd_mint: Pubkey,
+    pub zusd_decimals: u8, // read from the mint at Initialize
+    pub szusd_mint: Pubkey,
 
     //// bump seed
     pub authority_bump: u8,
     pub global_config_bump: u8,
 
-    // lending
-    pub ltv_ratio: u8, // in basis points (e.g., 7500 = 75%)
-    pub price: u64,    // zBTC price in USD (e.g., 50000 = $50,000)
-
     // staking
-    pub start_time: i64,
-    pub szusd_price_ratio: u64, // 1 -> 0.01%
+    pub start_time: i64,        // szusd_price_ratio has accrued up to this time
+    pub szusd_price_ratio: u64, // ZUSD per SZUSD in basis points (e.g., 10000 = 1 ZUSD)
+
+    // stability pool
+    pub total_staked_shares: u64, // SZUSD deposited into the stability pool, earns collateral gains
+    // Used slots come first, a market takes one at its first liquidation
+    pub stability_pool: [PoolMarket; MAX_POOL_MARKETS],
+
+    // global settlement
+    pub settled_at: i64,        // 0 while the protocol runs
+    pub settlement_debt: u64,   // raw ZUSD of settled debt not redeemed yet
+    pub settlement_value: u128, // USD (WAD) of the collateral seized for it
+    pub market_count: u32,      // markets opened by InitMarket
+    pub settlement_final: bool, // every market settled with no debt left, redemptions open
+
+    // peg stability module
+    pub psm_mint: Pubkey, // reserve stablecoin, Pubkey::default() until ConfigurePsm
+    pub psm_decimals: u8, // read from the mint at ConfigurePsm
+    pub psm_fee_in_bps: u16, // share of the ZUSD minted on PsmSwapIn paid into the insurance fund
+    pub psm_fee_out_bps: u16, // share of the ZUSD swapped on PsmSwapOut paid into the insurance fund
+    pub psm_ceiling: u64,     // raw reserve the PSM holds at most
+    pub psm_reserves: u64,    // raw reserve backing the ZUSD the PSM minted
+
+    // flash mint
+    pub flash_mint_due: u64, // raw ZUSD the running flash mint has to burn, 0 outside one
+
+    // flash loan
+    pub flash_loan_market: Pubkey, // market of the running flash loan, Pubkey::default() outside one
 }
 
 impl ZFubaoConfig {
-    pub const LEN: usize = 32 + // authority
-        32 + // zbtc_mint
+    pub const LEN: usize = 32 + // admin
+        32 + // authority
         32 + // zusd_mint
+        1 + // zusd_decimals
+        32 + // szusd_mint
         1 + // authority_bump
         1 + // global_config_bump
-        1 + // ltv_ratio
-        8 + // price
         8 + // start_time
-        8; // szusd_price_ratio
+        8 + // szusd_price_ratio
+        8 + // total_staked_shares
+        PoolMarket::LEN * MAX_POOL_MARKETS + // stability_pool
+        8 + // settled_at
+        8 + // settlement_debt
+        16 + // settlement_value
+        4 + // market_count
+        1 + // settlement_final
+        32 + // psm_mint
+        1 + // psm_decimals
+        2 + // psm_fee_in_bps
+        2 + // psm_fee_out_bps
+        8 + // psm_ceiling
+        8 + // psm_reserves
+        8 + // flash_mint_due
+        32; // flash_loan_market
+
+    pub fn is_settled(&self) -> bool {
+        self.settled_at != 0
+    }
+
+    // USD paid out per USD of settled ZUSD, at most 1. Below 1 the seized
+    // collateral did not cover the settled debt and every holder takes the
+    // same haircut.
+    pub fn settlement_rate(&self, zusd_decimals: u8) -> Result<Decimal, ProgramError> {
+        if self.settlement_debt == 0 {
+            return Ok(Decimal::zero());
+        }
+
+        let rate = Decimal::from_scaled_val(self.settlement_value).try_div(
+            Decimal::from_token_amount(self.settlement_debt, zusd_decimals)?,
+            Rounding::Down,
+        )?;
+        Ok(rate.min(Decimal::one()))
+    }
+
+    pub fn get_current_szusd_price_in_zusd(&self) -> Decimal {
+        Decimal::from_bps(self.szusd_price_ratio)
+    }
+
+    // Grows the szUSD price by one basis point per elapsed SZUSD_ACCRUAL_PERIOD.
+    // Only whole periods are consumed, so refreshing more often neither
+    // compounds the growth nor drops time.
+    pub fn accrue_szusd_price(&mut self, now: i64) -> Result<(), ProgramError> {
+        let periods = now
+            .checked_sub(self.start_time)
+            .ok_or(ProgramError::ArithmeticOverflow)?
+            / SZUSD_ACCRUAL_PERIOD;
+        if periods <= 0 {
+            return Ok(());
+        }
+
+        self.szusd_price_ratio = self
+            .szusd_price_ratio
+            .checked_add(periods as u64)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        self.start_time = self
+            .start_time
+            .checked_add(periods * SZUSD_ACCRUAL_PERIOD)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    // ZUSD of the staking vault the stability pool can burn. It stops short of
+    // the last basis point of the szUSD price, so staking keeps a price to mint at.
+    pub fn stability_pool_capacity(&self, vault_balance: u64) -> u64 {
+        (vault_balance as u128 * self.szusd_price_ratio.saturating_sub(1) as u128)
+            .checked_div(self.szusd_price_ratio as u128)
+            .unwrap_or(0) as u64
+    }
+
+    // Lowers the szUSD price by the share of the staking vault that was burned
+    pub fn write_down_szusd_price(
+        &mut self,
+        vault_balance: u64,
+        burned: u64,
+    ) -> Result<(), ProgramError> {
+        let remaining = vault_balance
+            .checked_sub(burned)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+
+        // Rounds down, stakers rather than the vault carry the remainder
+        self.szusd_price_ratio = (self.szusd_price_ratio as u128 * remaining as u128)
+            .checked_div(vault_balance as u128)
+            .ok_or(ProgramError::ArithmeticOverflow)? as u64;
+        Ok(())
+    }
+
+    // Spreads collateral seized in `market` over the recorded shares, taking the
+    // next free pool slot for a market's first liquidation
+    pub fn add_pool_gains(&mut self, market: &Pubkey, amount: u64) -> Result<(), ProgramError> {
+        let slot = self
+            .stability_pool
+            .iter()
+            .position(|pool_market| {
+                pool_market.market == *market || pool_market.market == Pubkey::default()
+            })
+            .ok_or(ZFubaoError::StabilityPoolFull)?;
+
+        let pool_market = &mut self.stability_pool[slot];
+        pool_market.market = *market;
+        pool_market.gains_per_share = Decimal::from_scaled_val(pool_market.gains_per_share)
+            .try_add(
+                Decimal::from_u64(amount)
+                    .try_div(Decimal::from_u64(self.total_staked_shares), Rounding::Down)?,
+            )?
+            .to_scaled_val()?;
+        Ok(())
+    }
+}
+
+// Collateral gains of the stability pool in one market
+#[derive(
+    BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub struct PoolMarket {
+    pub market: Pubkey,        // Pubkey::default() for a free slot
+    pub gains_per_share: u128, // WAD-scaled raw collateral per raw SZUSD share, only grows
+}
+
+impl PoolMarket {
+    pub const LEN: usize = 32 + // market
+        16; // gains_per_share
+}
+
+// Where a market's collateral price comes from
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriceSource {
+    Admin,             // `price` in whole USD, set with UpdateMarket
+    SzusdExchangeRate, // `price` is the global szusd_price_ratio, synced by RefreshPrice
+}
+
+impl PriceSource {
+    // USD per whole collateral token at a `price` of this source
+    pub fn to_usd(&self, price: u64) -> Decimal {
+        match self {
+            Self::Admin => Decimal::from_u64(price),
+            Self::SzusdExchangeRate => Decimal::from_bps(price),
+        }
+    }
+
+    pub fn max_ltv_ratio(&self) -> u16 {
+        match self {
+            Self::Admin => BPS_SCALER as u16,
+            Self::SzusdExchangeRate => MAX_SZUSD_LTV_RATIO,
+        }
+    }
+}
+
+// One collateral market, a PDA of its collateral mint. Every market lends the
+// same ZUSD; the ZUSD side of the global config is copied in at InitMarket so
+// lending instructions only need the market account.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone)]
+pub struct Market {
+    // general
+    //// account
+    pub authority: Pubkey,
+
+    //// mint
+    pub collateral_mint: Pubkey,
+    pub zusd_mint: Pubkey,
+    pub collateral_decimals: u8, // read from the mint at InitMarket
+    pub zusd_decimals: u8,
+
+    //// bump seed
+    pub authority_bump: u8,
+    pub market_bump: u8,
+
+    // lending
+    pub ltv_ratio: u16, // in basis points (e.g., 7500 = 75%)
+    pub price_source: PriceSource,
+    pub price: u64,       // collateral price, see PriceSource (e.g., 50000 = $50,000)
+    pub deposit_cap: u64, // raw collateral the market accepts in total
+    pub borrow_cap: u64,  // raw ZUSD the market lends in total
+    pub total_deposits: u64,
+    pub total_borrowed: u64,
+
+    // global settlement
+    pub settled: bool,           // price frozen and borrowing off for good
+    pub settled_collateral: u64, // raw collateral seized from settled obligations, redeemable for ZUSD
+
+    // flash loan
+    pub flash_loan_due: u64, // raw collateral the running flash loan has to return, 0 outside one
+
+    // redemption
+    pub redemption_price: u64, // price at InitMarket, see PriceSource, fixes the redemption buckets
+    // Obligations of the market per redemption bucket, riskiest first
+    pub redemption_buckets: [u32; REDEMPTION_BUCKETS],
+}
+
+impl Market {
+    pub const LEN: usize = 32 + // authority
+        32 + // collateral_mint
+        32 + // zusd_mint
+        1 + // collateral_decimals
+        1 + // zusd_decimals
+        1 + // authority_bump
+        1 + // market_bump
+        2 + // ltv_ratio
+        1 + // price_source
+        8 + // price
+        8 + // deposit_cap
+        8 + // borrow_cap
+        8 + // total_deposits
+        8 + // total_borrowed
+        1 + // settled
+        8 + // settled_collateral
+        8 + // flash_loan_due
+        8 + // redemption_price
+        4 * REDEMPTION_BUCKETS; // redemption_buckets
+
+    pub fn ltv(&self) -> Decimal {
+        Decimal::from_bps(self.ltv_ratio as u64)
+    }
+
+    // USD per whole collateral token, ZUSD is pegged at $1
+    pub fn collateral_price(&self) -> Decimal {
+        self.price_source.to_usd(self.price)
+    }
+
+    // Redemption bucket of a position holding `collateral_amount` in this market
+    // against `zusd_amount` of debt, see REDEMPTION_BUCKETS
+    pub fn redemption_bucket(
+        &self,
+        collateral_amount: u64,
+        zusd_amount: u64,
+    ) -> Result<u8, ProgramError> {
+        if collateral_amount == 0 || zusd_amount == 0 {
+            return Ok(NO_REDEMPTION_BUCKET);
+        }
+
+        let collateral_ratio =
+            Decimal::from_token_amount(collateral_amount, self.collateral_decimals)?
+                .try_mul(
+                    self.price_source.to_usd(self.redemption_price),
+                    Rounding::Down,
+                )?
+                .try_div(self.zusd_value(zusd_amount)?, Rounding::Down)?;
+
+        let bucket = collateral_ratio.log2_eighths().map_or(0, |eighths| {
+            (eighths + REDEMPTION_BUCKETS as i32 / 2).clamp(0, REDEMPTION_BUCKETS as i32 - 1)
+        });
+        Ok(bucket as u8)
+    }
+
+    // USD value of a raw collateral amount
+    pub fn collateral_value(&self, collateral_amount: u64) -> Result<Decimal, ProgramError> {
+        Decimal::from_token_amount(collateral_amount, self.collateral_decimals)?
+            .try_mul(self.collateral_price(), Rounding::Down)
+    }
+
+    // USD value of a raw ZUSD amount, ZUSD is pegged at $1
+    pub fn zusd_value(&self, zusd_amount: u64) -> Result<Decimal, ProgramError> {
+        Decimal::from_token_amount(zusd_amount, self.zusd_decimals)
+    }
+
+    // Raw collateral amount worth the given USD value
+    pub fn collateral_amount(
+        &self,
+        value: Decimal,
+        rounding: Rounding,
+    ) -> Result<u64, ProgramError> {
+        value
+            .try_div(self.collateral_price(), rounding)?
+            .to_token_amount(self.collateral_decimals, rounding)
+    }
+
+    // Raw ZUSD amount worth the given USD value
+    pub fn zusd_amount(&self, value: Decimal, rounding: Rounding) -> Result<u64, ProgramError> {
+        value.to_token_amount(self.zusd_decimals, rounding)
+    }
+
+    pub fn add_deposits(&mut self, amount: u64) -> Result<(), ProgramError> {
+        self.total_deposits = self
+            .total_deposits
+            .checked_add(amount)
+            .filter(|total| *total <= self.deposit_cap)
+            .ok_or(ZFubaoError::DepositCapExceeded)?;
+        Ok(())
+    }
+
+    pub fn add_borrowed(&mut self, amount: u64) -> Result<(), ProgramError> {
+        self.total_borrowed = self
+            .total_borrowed
+            .checked_add(amount)
+            .filter(|total| *total <= self.borrow_cap)
+            .ok_or(ZFubaoError::BorrowCapExceeded)?;
+        Ok(())
+    }
+
+    // Lowering a cap never traps funds, so removals only guard against underflow
+    pub fn remove_deposits(&mut self, amount: u64) -> Result<(), ProgramError> {
+        self.total_deposits = self
+            .total_deposits
+            .checked_sub(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
 
-    pub fn get_current_szusd_price_in_zusd(&self) -> u64 {
-        self.szusd_price_ratio / 10000
+    pub fn remove_borrowed(&mut self, amount: u64) -> Result<(), ProgramError> {
+        self.total_borrowed = self
+            .total_borrowed
+            .checked_sub(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
     }
 }
 
 // Lending
-#[derive(BorshSerialize, BorshDeserialize, Debug)]
+// Deposits and repayments are open to anyone, so only Full grants extra rights
+// today; the narrower scopes are kept for existing delegations
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelegatePermission {
+    None,
+    RepayOnly,
+    DepositOnly,
+    Full, // deposit, repay, borrow and withdraw
+}
+
+impl DelegatePermission {
+    pub fn covers(&self, required: DelegatePermission) -> bool {
+        match self {
+            Self::None => false,
+            Self::Full => true,
+            _ => *self == required,
+        }
+    }
+}
+
+// Collateral an obligation holds in one market
+#[derive(
+    BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub struct CollateralDeposit {
+    pub market: Pubkey, // Pubkey::default() for a free slot
+    pub amount: u64,    // raw collateral of the market's mint
+}
+
+impl CollateralDeposit {
+    pub const LEN: usize = 32 + // market
+        8; // amount
+}
+
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug)]
 pub struct Obligation {
-    pub zbtc_deposit: u64,
+    pub owner: Pubkey,    // kept first so getProgramAccounts can memcmp on it
+    pub creator: Pubkey,  // wallet that opened the obligation, used in the PDA seeds
+    pub index: u16,       // position index used in the PDA seeds
+    pub market: Pubkey,   // market the debt lives in, used in the PDA seeds
+    pub delegate: Pubkey, // Pubkey::default() when no delegate is set
+    pub delegate_permission: DelegatePermission,
+    // Used slots come first, in the order instructions expect their markets
+    pub deposits: [CollateralDeposit; MAX_DEPOSITS],
     pub zusd_borrowed: u64,
+    pub auctions: u8,          // running auctions of its collateral
+    pub redemption_bucket: u8, // counted in its market's redemption_buckets, see REDEMPTION_BUCKETS
 }
 
 impl Obligation {
-    pub const LEN: usize =
-        8 + // zbtc_deposit
-        8; // zusd_borrowed
+    pub const LEN: usize = 32 + // owner
+        32 + // creator
+        2 + // index
+        32 + // market
+        32 + // delegate
+        1 + // delegate_permission
+        CollateralDeposit::LEN * MAX_DEPOSITS + // deposits
+        8 + // zusd_borrowed
+        1 + // auctions
+        1; // redemption_bucket
+
+    pub const OWNER_OFFSET: usize = 0;
+
+    pub fn deposits(&self) -> impl Iterator<Item = &CollateralDeposit> {
+        self.deposits
+            .iter()
+            .take_while(|deposit| deposit.market != Pubkey::default())
+    }
+
+    // Raw collateral held in `market`
+    pub fn deposited(&self, market: &Pubkey) -> u64 {
+        self.deposits()
+            .find(|deposit| deposit.market == *market)
+            .map_or(0, |deposit| deposit.amount)
+    }
+
+    // Adds to the deposit in `market`, taking the next free slot for a new market
+    pub fn add_deposit(&mut self, market: &Pubkey, amount: u64) -> Result<(), ProgramError> {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let existing = self
+            .deposits()
+            .position(|deposit| deposit.market == *market);
+        let slot = match existing {
+            Some(slot) => slot,
+            None => {
+                let slot = self.deposits().count();
+                if slot == MAX_DEPOSITS {
+                    return Err(ZFubaoError::TooManyDeposits.into());
+                }
+                self.deposits[slot].market = *market;
+                slot
+            }
+        };
+
+        self.deposits[slot].amount = self.deposits[slot]
+            .amount
+            .checked_add(amount)
+            .ok_or(ProgramError::ArithmeticOverflow)?;
+        Ok(())
+    }
+
+    // Takes from the deposit in `market`, an emptied slot is freed and the
+    // later deposits move up
+    pub fn remove_deposit(&mut self, market: &Pubkey, amount: u64) -> Result<(), ProgramError> {
+        if amount == 0 {
+            return Ok(());
+        }
+
+        let slot = self
+            .deposits()
+            .position(|deposit| deposit.market == *market)
+            .ok_or(ZFubaoError::WithdrawLimitExceeded)?;
+
+        self.deposits[slot].amount = self.deposits[slot]
+            .amount
+            .checked_sub(amount)
+            .ok_or(ZFubaoError::WithdrawLimitExceeded)?;
+
+        if self.deposits[slot].amount == 0 {
+            self.deposits.copy_within(slot + 1.., slot);
+            self.deposits[MAX_DEPOSITS - 1] = CollateralDeposit::default();
+        }
+        Ok(())
+    }
+
+    // The owner can always operate the obligation, the delegate only within its scope
+    pub fn can_be_operated_by(&self, signer: &Pubkey, required: DelegatePermission) -> bool {
+        if *signer == self.owner {
+            return true;
+        }
+
+        *signer == self.delegate
+            && self.delegate != Pubkey::default()
+            && self.delegate_permission.covers(required)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CollateralState {
+    Healthy,
+    Undercollateralized, // debt exceeds the borrow limit, e.g. after a price drop
+}
+
+// Where an obligation stands against the LTV limit, seen from one of its markets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PositionStatus {
+    pub state: CollateralState,
+    pub borrow_limit: u64,     // raw ZUSD all the collateral can back
+    pub max_borrowable: u64,   // raw ZUSD, zero when undercollateralized
+    pub max_withdrawable: u64, // raw collateral of that market, zero when undercollateralized
+}
+
+impl PositionStatus {
+    pub fn is_undercollateralized(&self) -> bool {
+        self.state == CollateralState::Undercollateralized
+    }
+}
+
+// One obligation's part of a Redeem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redemption {
+    pub zusd_amount: u64,       // raw ZUSD of debt cancelled
+    pub insurance_fee: u64,     // raw ZUSD paid into the insurance fund on top
+    pub collateral_amount: u64, // raw collateral paid to the redeemer
+    pub fee: u64,               // raw collateral left in the obligation
+}
+
+// One liquidation absorbed by the stability pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Liquidation {
+    pub zusd_amount: u64,       // raw ZUSD of debt cancelled
+    pub collateral_amount: u64, // raw collateral seized, bonus included
+}
+
+// One deleverage absorbed by the stability pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deleveraging {
+    pub zusd_amount: u64,       // raw ZUSD of debt cancelled
+    pub collateral_amount: u64, // raw collateral sold, fee included
+    pub fee: u64,               // raw collateral on top of the oracle price
 }
 
-pub fn find_obligation_pda(user: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
-    let seeds = &[OBLIGATION_SEED, user.as_ref()];
+// Collateral of an undercollateralized obligation on sale for ZUSD at a falling
+// price. The ZUSD paid cancels the obligation's debt, what is left once the debt
+// is covered goes back to the owner.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug)]
+pub struct Auction {
+    pub obligation: Pubkey,
+    pub market: Pubkey, // market of the collateral on sale
+    pub owner: Pubkey,  // obligation owner when the auction started, gets the leftovers
+    pub bump: u8,
+    pub start_time: i64,
+    pub start_price: u128,      // USD per whole collateral token, WAD-scaled
+    pub floor_price: u128,      // USD per whole collateral token, WAD-scaled
+    pub collateral_amount: u64, // raw collateral left to sell
+}
+
+impl Auction {
+    pub const LEN: usize = 32 + // obligation
+        32 + // market
+        32 + // owner
+        1 + // bump
+        8 + // start_time
+        16 + // start_price
+        16 + // floor_price
+        8; // collateral_amount
+
+    // USD per whole collateral token at `now`
+    pub fn current_price(&self, now: i64) -> Result<Decimal, ProgramError> {
+        let elapsed = now
+            .saturating_sub(self.start_time)
+            .clamp(0, AUCTION_DURATION);
+        let start_price = Decimal::from_scaled_val(self.start_price);
+        let decay = start_price
+            .try_sub(Decimal::from_scaled_val(self.floor_price))?
+            .try_mul(Decimal::from_u64(elapsed as u64), Rounding::Up)?
+            .try_div(Decimal::from_u64(AUCTION_DURATION as u64), Rounding::Up)?;
+
+        // Rounds up, the price never drops below the curve
+        start_price.try_sub(decay)
+    }
+}
+
+// One bid's fill of an auction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuctionFill {
+    pub zusd_amount: u64,       // raw ZUSD paid, penalty included
+    pub collateral_amount: u64, // raw collateral paid to the bidder
+    pub penalty: u64,           // raw ZUSD paid into the insurance fund
+}
+
+// One ZUSD redemption against the settled collateral of a market
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SettlementRedemption {
+    pub zusd_amount: u64,       // raw ZUSD burned
+    pub collateral_amount: u64, // raw collateral paid to the holder
+    pub value: Decimal,         // USD paid out of the settlement value
+}
+
+// One swap through the peg stability module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PsmSwap {
+    pub amount_out: u64, // raw ZUSD minted or raw reserve released
+    pub fee: u64,        // raw ZUSD paid into the insurance fund
+}
+
+// Returned by GetObligationHealth, Decimal values are WAD-scaled (1.0 == 10^18)
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug, PartialEq, Eq)]
+pub struct ObligationHealth {
+    pub collateral_value: u128, // USD, all deposits
+    pub debt_value: u128,       // USD
+    pub health_factor: u128,    // borrow limit / debt, u128::MAX without debt
+    pub max_borrowable: u64,    // raw ZUSD
+    pub max_withdrawable: u64,  // raw collateral of the queried market
+    // Price in USD of the queried market's collateral at which health hits 1, other
+    // prices unchanged. 0 when the other deposits cover the debt on their own.
+    pub liquidation_price: u128,
+}
+
+pub fn find_market_pda(collateral_mint: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[MARKET_SEED, collateral_mint.as_ref()], program_id)
+}
+
+pub fn find_obligation_pda(
+    market: &Pubkey,
+    user: &Pubkey,
+    index: u16,
+    program_id: &Pubkey,
+) -> (Pubkey, u8) {
+    let seeds = &[
+        OBLIGATION_SEED,
+        market.as_ref(),
+        user.as_ref(),
+        &index.to_le_bytes(),
+    ];
     Pubkey::find_program_address(seeds, program_id)
 }
+
+// A staker's share of the stability pool. Shares are SZUSD burned by
+// DepositSZUSD and minted back by WithdrawSZUSD, so they cannot change hands
+// without the program settling the gains first.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug)]
+pub struct Staker {
+    pub owner: Pubkey,
+    pub bump: u8,
+    pub shares: u64, // raw SZUSD
+    // Indexed like the global config's stability_pool
+    pub gains: [CollateralGain; MAX_POOL_MARKETS],
+}
+
+impl Staker {
+    pub const LEN: usize = 32 + // owner
+        1 + // bump
+        8 + // shares
+        CollateralGain::LEN * MAX_POOL_MARKETS; // gains
+
+    // Books the gains accrued on the current shares. Run before shares change.
+    pub fn settle_gains(&mut self, global_config: &ZFubaoConfig) -> Result<(), ProgramError> {
+        let shares = Decimal::from_u64(self.shares);
+
+        for (gain, pool_market) in self.gains.iter_mut().zip(&global_config.stability_pool) {
+            let accrued = Decimal::from_scaled_val(pool_market.gains_per_share)
+                .try_sub(Decimal::from_scaled_val(gain.snapshot))?
+                .try_mul(shares, Rounding::Down)?
+                .to_u64(Rounding::Down)?;
+
+            gain.pending = gain
+                .pending
+                .checked_add(accrued)
+                .ok_or(ProgramError::ArithmeticOverflow)?;
+            gain.snapshot = pool_market.gains_per_share;
+        }
+        Ok(())
+    }
+}
+
+// Collateral a staker earned in one pool market
+#[derive(
+    BorshSerialize, BorshDeserialize, BorshSchema, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub struct CollateralGain {
+    pub snapshot: u128, // gains_per_share when last settled
+    pub pending: u64,   // raw collateral to claim
+}
+
+impl CollateralGain {
+    pub const LEN: usize = 16 + // snapshot
+        8; // pending
+}
+
+pub fn find_staker_pda(owner: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[STAKER_SEED, owner.as_ref()], program_id)
+}
+
+// Owner of the insurance fund, the ZUSD associated token account of this PDA
+pub fn find_insurance_pda(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[INSURANCE_SEED], program_id)
+}
+
+pub fn find_auction_pda(obligation: &Pubkey, market: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[AUCTION_SEED, obligation.as_ref(), market.as_ref()],
+        program_id,
+    )
+}
+
+// An owner's standing order to deleverage an obligation once its health factor
+// drops below the threshold. Keepers execute it for a tip in collateral.
+#[derive(BorshSerialize, BorshDeserialize, BorshSchema, Debug)]
+pub struct Trigger {
+    pub obligation: Pubkey,
+    pub owner: Pubkey,  // obligation owner that set it
+    pub market: Pubkey, // market of the collateral sold and tipped out
+    pub bump: u8,
+    pub threshold_bps: u16, // health factor below which keepers can execute
+    pub target_bps: u16,    // health factor an execution restores
+    pub keeper_tip: u64,    // raw collateral paid per full execution
+}
+
+impl Trigger {
+    pub const LEN: usize = 32 + // obligation
+        32 + // owner
+        32 + // market
+        1 + // bump
+        2 + // threshold_bps
+        2 + // target_bps
+        8; // keeper_tip
+}
+
+pub fn find_trigger_pda(obligation: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[TRIGGER_SEED, obligation.as_ref()], program_id)
+}
+
+// getProgramAccounts filters listing every obligation of `owner`:
+// a data size filter of Obligation::LEN plus this memcmp (offset, bytes)
+pub fn obligation_owner_filter(owner: &Pubkey) -> (usize, [u8; 32]) {
+    (Obligation::OWNER_OFFSET, owner.to_bytes())
+}